@@ -0,0 +1,36 @@
+//! Times the run loop on a tight arithmetic loop over locals, the workload the
+//! dispatch changes target. Run with `cargo bench --bench dispatch`.
+
+use clox_rs::lox::vm::{InterpretResult, VM};
+
+use std::time::{Duration, Instant};
+
+const WORKLOAD: &str = "
+fun work() {
+    var sum = 0;
+    var i = 0;
+    while (i < 1000000) {
+        sum = sum + i * 2 - i;
+        i = i + 1;
+    }
+    return sum;
+}
+var result = work();
+";
+
+const RUNS: usize = 5;
+
+fn main() {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let mut vm = VM::new(false);
+        let start = Instant::now();
+        let result = vm.interpret(WORKLOAD);
+        let elapsed = start.elapsed();
+
+        assert!(matches!(result, InterpretResult::Ok), "Benchmark script failed");
+        best = best.min(elapsed);
+    }
+
+    println!("dispatch: best of {} runs: {:.1} ms", RUNS, best.as_secs_f64() * 1000.0);
+}
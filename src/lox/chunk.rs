@@ -1,19 +1,36 @@
 use crate::lox::value::{Value, ValueArray};
 use super::object::{StringId, StringLiteralStorage};
 
-use std::fmt::Display;
+use std::collections::HashSet;
+use std::fmt::{Display, Write};
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(u8)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
+    Int,
     StringLiteral,
+    StringLiteralLong,
     Nil,
     True,
     False,
     Pop,
+    GetLocal,
+    SetLocal,
     GetGlobal,
+    GetGlobalLong,
     DefineGlobal,
+    DefineGlobalLong,
     SetGlobal,
+    SetGlobalLong,
+    DeleteGlobal,
+    DeleteGlobalLong,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    SetProperty,
+    GetSuper,
     Equal,
     Greater,
     Less,
@@ -23,22 +40,63 @@ pub enum OpCode {
     Divide,
     Not,
     Negate,
+    TypeOf,
     Print,
+    EPrint,
+    PrintElement,
+    Throw,
+    Jump,
+    JumpIfFalse,
+    JumpIfNotNil,
+    PopJumpIfFalse,
+    Loop,
+    Call,
+    Invoke,
+    SuperInvoke,
+    Closure,
+    CloseUpvalue,
     Return,
+    Class,
+    Inherit,
+    Method,
+    BuildMap,
+    BuildList,
+    GetIndex,
+    SetIndex,
+    Unpack,
+    GetIter,
+    IterNext,
+    PushHandler,
+    PopHandler,
 }
 
 impl Display for OpCode {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             OpCode::Constant => write!(f, "OP_CONSTANT"),
+            OpCode::ConstantLong => write!(f, "OP_CONSTANT_LONG"),
+            OpCode::Int => write!(f, "OP_INT"),
             OpCode::StringLiteral => write!(f, "OP_STRING_LITERAL"),
+            OpCode::StringLiteralLong => write!(f, "OP_STRING_LITERAL_LONG"),
             OpCode::Nil => write!(f, "OP_NIL"),
             OpCode::True => write!(f, "OP_TRUE"),
             OpCode::False => write!(f, "OP_FALSE"),
             OpCode::Pop => write!(f, "OP_POP"),
+            OpCode::GetLocal => write!(f, "OP_GET_LOCAL"),
+            OpCode::SetLocal => write!(f, "OP_SET_LOCAL"),
             OpCode::GetGlobal => write!(f, "OP_GET_GLOBAL"),
+            OpCode::GetGlobalLong => write!(f, "OP_GET_GLOBAL_LONG"),
             OpCode::DefineGlobal => write!(f, "OP_DEFINE_GLOBAL"),
+            OpCode::DefineGlobalLong => write!(f, "OP_DEFINE_GLOBAL_LONG"),
             OpCode::SetGlobal => write!(f, "OP_SET_GLOBAL"),
+            OpCode::SetGlobalLong => write!(f, "OP_SET_GLOBAL_LONG"),
+            OpCode::DeleteGlobal => write!(f, "OP_DELETE_GLOBAL"),
+            OpCode::DeleteGlobalLong => write!(f, "OP_DELETE_GLOBAL_LONG"),
+            OpCode::GetUpvalue => write!(f, "OP_GET_UPVALUE"),
+            OpCode::SetUpvalue => write!(f, "OP_SET_UPVALUE"),
+            OpCode::GetProperty => write!(f, "OP_GET_PROPERTY"),
+            OpCode::SetProperty => write!(f, "OP_SET_PROPERTY"),
+            OpCode::GetSuper => write!(f, "OP_GET_SUPER"),
             OpCode::Equal => write!(f, "OP_EQUAL"),
             OpCode::Greater => write!(f, "OP_GREATER"),
             OpCode::Less => write!(f, "OP_LESS"),
@@ -48,43 +106,155 @@ impl Display for OpCode {
             OpCode::Divide => write!(f, "OP_DIVIDE"),
             OpCode::Not => write!(f, "OP_NOT"),
             OpCode::Negate => write!(f, "OP_NEGATE"),
+            OpCode::TypeOf => write!(f, "OP_TYPEOF"),
             OpCode::Print => write!(f, "OP_PRINT"),
+            OpCode::EPrint => write!(f, "OP_EPRINT"),
+            OpCode::PrintElement => write!(f, "OP_PRINT_ELEMENT"),
+            OpCode::Throw => write!(f, "OP_THROW"),
+            OpCode::Jump => write!(f, "OP_JUMP"),
+            OpCode::JumpIfFalse => write!(f, "OP_JUMP_IF_FALSE"),
+            OpCode::JumpIfNotNil => write!(f, "OP_JUMP_IF_NOT_NIL"),
+            OpCode::PopJumpIfFalse => write!(f, "OP_POP_JUMP_IF_FALSE"),
+            OpCode::Loop => write!(f, "OP_LOOP"),
+            OpCode::Call => write!(f, "OP_CALL"),
+            OpCode::Invoke => write!(f, "OP_INVOKE"),
+            OpCode::SuperInvoke => write!(f, "OP_SUPER_INVOKE"),
+            OpCode::Closure => write!(f, "OP_CLOSURE"),
+            OpCode::CloseUpvalue => write!(f, "OP_CLOSE_UPVALUE"),
             OpCode::Return => write!(f, "OP_RETURN"),
+            OpCode::Class => write!(f, "OP_CLASS"),
+            OpCode::Inherit => write!(f, "OP_INHERIT"),
+            OpCode::Method => write!(f, "OP_METHOD"),
+            OpCode::BuildMap => write!(f, "OP_BUILD_MAP"),
+            OpCode::BuildList => write!(f, "OP_BUILD_LIST"),
+            OpCode::GetIndex => write!(f, "OP_GET_INDEX"),
+            OpCode::SetIndex => write!(f, "OP_SET_INDEX"),
+            OpCode::Unpack => write!(f, "OP_UNPACK"),
+            OpCode::GetIter => write!(f, "OP_GET_ITER"),
+            OpCode::IterNext => write!(f, "OP_ITER_NEXT"),
+            OpCode::PushHandler => write!(f, "OP_PUSH_HANDLER"),
+            OpCode::PopHandler => write!(f, "OP_POP_HANDLER"),
         }
     }
 }
 
 impl OpCode {
     pub fn from_u8(value: u8) -> OpCode {
+        OpCode::try_from_u8(value).expect("Invalid opcode")
+    }
+
+    pub fn try_from_u8(value: u8) -> Option<OpCode> {
         match value {
-            0 => OpCode::Constant,
-            1 => OpCode::StringLiteral,
-            2 => OpCode::Nil,
-            3 => OpCode::True,
-            4 => OpCode::False,
-            5 => OpCode::Pop,
-            6 => OpCode::GetGlobal,
-            7 => OpCode::DefineGlobal,
-            8 => OpCode::SetGlobal,
-            9 => OpCode::Equal,
-            10 => OpCode::Greater,
-            11 => OpCode::Less,
-            12 => OpCode::Add,
-            13 => OpCode::Subtract,
-            14 => OpCode::Multiply,
-            15 => OpCode::Divide,
-            16 => OpCode::Not,
-            17 => OpCode::Negate,
-            18 => OpCode::Print,
-            19 => OpCode::Return,
-            _ => panic!("Invalid opcode"),
+            0 => Some(OpCode::Constant),
+            1 => Some(OpCode::ConstantLong),
+            2 => Some(OpCode::Int),
+            3 => Some(OpCode::StringLiteral),
+            4 => Some(OpCode::StringLiteralLong),
+            5 => Some(OpCode::Nil),
+            6 => Some(OpCode::True),
+            7 => Some(OpCode::False),
+            8 => Some(OpCode::Pop),
+            9 => Some(OpCode::GetLocal),
+            10 => Some(OpCode::SetLocal),
+            11 => Some(OpCode::GetGlobal),
+            12 => Some(OpCode::GetGlobalLong),
+            13 => Some(OpCode::DefineGlobal),
+            14 => Some(OpCode::DefineGlobalLong),
+            15 => Some(OpCode::SetGlobal),
+            16 => Some(OpCode::SetGlobalLong),
+            17 => Some(OpCode::DeleteGlobal),
+            18 => Some(OpCode::DeleteGlobalLong),
+            19 => Some(OpCode::GetUpvalue),
+            20 => Some(OpCode::SetUpvalue),
+            21 => Some(OpCode::GetProperty),
+            22 => Some(OpCode::SetProperty),
+            23 => Some(OpCode::GetSuper),
+            24 => Some(OpCode::Equal),
+            25 => Some(OpCode::Greater),
+            26 => Some(OpCode::Less),
+            27 => Some(OpCode::Add),
+            28 => Some(OpCode::Subtract),
+            29 => Some(OpCode::Multiply),
+            30 => Some(OpCode::Divide),
+            31 => Some(OpCode::Not),
+            32 => Some(OpCode::Negate),
+            33 => Some(OpCode::TypeOf),
+            34 => Some(OpCode::Print),
+            35 => Some(OpCode::EPrint),
+            36 => Some(OpCode::PrintElement),
+            37 => Some(OpCode::Throw),
+            38 => Some(OpCode::Jump),
+            39 => Some(OpCode::JumpIfFalse),
+            40 => Some(OpCode::JumpIfNotNil),
+            41 => Some(OpCode::PopJumpIfFalse),
+            42 => Some(OpCode::Loop),
+            43 => Some(OpCode::Call),
+            44 => Some(OpCode::Invoke),
+            45 => Some(OpCode::SuperInvoke),
+            46 => Some(OpCode::Closure),
+            47 => Some(OpCode::CloseUpvalue),
+            48 => Some(OpCode::Return),
+            49 => Some(OpCode::Class),
+            50 => Some(OpCode::Inherit),
+            51 => Some(OpCode::Method),
+            52 => Some(OpCode::BuildMap),
+            53 => Some(OpCode::BuildList),
+            54 => Some(OpCode::GetIndex),
+            55 => Some(OpCode::SetIndex),
+            56 => Some(OpCode::Unpack),
+            57 => Some(OpCode::GetIter),
+            58 => Some(OpCode::IterNext),
+            59 => Some(OpCode::PushHandler),
+            60 => Some(OpCode::PopHandler),
+            _ => None,
+        }
+    }
+}
+
+/// `ConstantLong` takes a 24-bit index, so this is as large as the pool can get.
+const MAX_CONSTANTS: usize = 1 << 24;
+
+/// Where `add_or_retrieve_constant` put a value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConstantSlot {
+    Added(usize),
+    Existing(usize),
+}
+
+impl ConstantSlot {
+    pub fn index(&self) -> usize {
+        match self {
+            ConstantSlot::Added(idx) | ConstantSlot::Existing(idx) => *idx,
         }
     }
 }
 
+/// The bytes of a compiled source an instruction came from. `source` indexes the chunk's sources.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Span {
+    pub source: usize,
+    pub start: usize,
+    pub length: usize,
+    /// The line the span starts on, which can differ from the instruction's line.
+    pub line: u32,
+}
+
+/// The code from `start` up to the next run's start came from `line`.
+#[derive(Clone)]
+struct LineRun {
+    start: usize,
+    line: u32,
+}
+
+#[derive(Clone)]
 pub struct Chunk {
     code: Vec<u8>,
-    lines: Vec<u32>,
+    /// Run-length encoded, since most lines compile to several bytes in a row.
+    lines: Vec<LineRun>,
+    /// Parallel to `code`. Only instructions that can fail at runtime get a span.
+    spans: Vec<Option<Span>>,
+    /// Every source compiled into this chunk, so spans can be shown after the compile.
+    sources: Vec<String>,
     constants: ValueArray,
     string_literals: StringLiteralStorage,
 }
@@ -94,39 +264,106 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
+            sources: Vec::new(),
             constants: ValueArray::new(),
             string_literals: StringLiteralStorage::new(),
         }
     }
 
     pub fn write(&mut self, opcode: OpCode, line: u32) {
-        self.code.push(opcode as u8);
-        self.lines.push(line);
+        self.push_byte(opcode as u8, line);
     }
 
     pub fn write_u8(&mut self, v: u8, line: u32) {
+        self.push_byte(v, line);
+    }
+
+    fn push_byte(&mut self, v: u8, line: u32) {
+        if self.lines.last().is_none_or(|run| run.line != line) {
+            self.lines.push(LineRun { start: self.code.len(), line });
+        }
         self.code.push(v);
-        self.lines.push(line);
+        self.spans.push(None);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> Result<u8, String> {
-        if self.constants.values.len() >= u8::MAX as usize {
+    pub fn patch_u8(&mut self, offset: usize, v: u8) {
+        self.code[offset] = v;
+    }
+
+    pub fn code_size(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Strings can be constants too, as long as they're literals of this chunk; runtime strings
+    /// don't exist yet when code is compiled.
+    pub fn add_constant(&mut self, value: Value) -> Result<usize, String> {
+        if self.constants.values.len() >= MAX_CONSTANTS {
             return Err(String::from("Too many constants in one chunk"));
         }
+        if let Value::String(id) = &value {
+            if !id.is_literal() || id.0 as usize >= self.string_literals.count() {
+                return Err(String::from("Only string literals of this chunk can be constants"));
+            }
+        }
         self.constants.write(value);
-        
-        Ok((self.constants.values.len() - 1) as u8)
+        Ok(self.constants.values.len() - 1)
+    }
+
+    /// Like `add_constant`, but numbers and strings already in the pool are reused.
+    /// Numbers match by bit pattern, so `0` and `-0` keep separate slots.
+    pub fn add_or_retrieve_constant(&mut self, value: Value) -> Result<ConstantSlot, String> {
+        let existing = match &value {
+            Value::Number(n) => self.constants.values.iter().position(|v| {
+                matches!(v, Value::Number(m) if m.to_bits() == n.to_bits())
+            }),
+            Value::String(id) => self.constants.values.iter().position(|v| {
+                matches!(v, Value::String(other) if other == id)
+            }),
+            _ => None,
+        };
+
+        if let Some(idx) = existing {
+            return Ok(ConstantSlot::Existing(idx));
+        }
+
+        self.add_constant(value).map(ConstantSlot::Added)
+    }
+
+    /// Adds `value` to the pool and writes the instruction that loads it: `Constant` with a
+    /// one-byte index when it fits, otherwise `ConstantLong` with a 24-bit big-endian index.
+    pub fn write_constant(&mut self, value: Value, line: u32) -> Result<ConstantSlot, String> {
+        let slot = self.add_or_retrieve_constant(value)?;
+        let idx = slot.index();
+
+        match u8::try_from(idx) {
+            Ok(idx) => {
+                self.write(OpCode::Constant, line);
+                self.write_u8(idx, line);
+            },
+            Err(_) => {
+                self.write(OpCode::ConstantLong, line);
+                self.write_u8((idx >> 16) as u8, line);
+                self.write_u8((idx >> 8) as u8, line);
+                self.write_u8(idx as u8, line);
+            },
+        }
+
+        Ok(slot)
     }
 
     pub fn write_string_literal_id(&mut self, id: &StringId, line: u32) -> Result<(), String> {
-        if id.is_literal() {
-            let id = id.0 as u8;
-            self.code.push(id);
-            self.lines.push(line);
+        if !id.is_literal() {
+            return Err(String::from("Invalid string literal id"));
+        }
 
-            Ok(())
-        } else {
-            Err(String::from("Invalid string literal id"))
+        match u8::try_from(id.0) {
+            Ok(id) => {
+                self.push_byte(id, line);
+
+                Ok(())
+            },
+            Err(_) => Err(format!("String literal id {} doesn't fit in one byte", id.0)),
         }
     }
 
@@ -137,6 +374,51 @@ impl Chunk {
         }
     }
 
+    /// Interns `string` and writes the `StringLiteral` instruction pushing it. Equal strings
+    /// share one id. The literal is allocated first, so hitting the literal limit doesn't
+    /// leave a dangling `StringLiteral` opcode in the chunk.
+    pub fn emit_string(&mut self, string: &str, line: u32) -> Result<StringId, String> {
+        let id = self.add_or_retrieve_string_literal(string)?;
+        self.write_literal_instruction(OpCode::StringLiteral, OpCode::StringLiteralLong, &id, line)?;
+
+        Ok(id)
+    }
+
+    /// Writes `GetGlobal`, `SetGlobal`, `DefineGlobal` or `DeleteGlobal` for the global `id`, switching to the
+    /// long form of `opcode` when the id doesn't fit in one byte, like `write_constant` does.
+    pub fn write_global(&mut self, opcode: OpCode, id: &StringId, line: u32) -> Result<(), String> {
+        let long = match opcode {
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::DeleteGlobal => OpCode::DeleteGlobalLong,
+            _ => return Err(format!("{} has no long form", opcode)),
+        };
+
+        self.write_literal_instruction(opcode, long, id, line)
+    }
+
+    fn write_literal_instruction(&mut self, short: OpCode, long: OpCode, id: &StringId, line: u32) -> Result<(), String> {
+        if !id.is_literal() {
+            return Err(String::from("Invalid string literal id"));
+        }
+
+        match u8::try_from(id.0) {
+            Ok(idx) => {
+                self.write(short, line);
+                self.write_u8(idx, line);
+            },
+            Err(_) => {
+                self.write(long, line);
+                self.write_u8((id.0 >> 16) as u8, line);
+                self.write_u8((id.0 >> 8) as u8, line);
+                self.write_u8(id.0 as u8, line);
+            },
+        }
+
+        Ok(())
+    }
+
     pub fn byte(&self, offset: usize) -> u8 {
         self.code[offset]
     }
@@ -145,86 +427,631 @@ impl Chunk {
         self.constants.read(self.code[offset] as usize)
     }
 
+    /// Reads the 24-bit constant index `ConstantLong` stores at `offset`.
+    pub fn read_constant_long(&self, offset: usize) -> &Value {
+        self.constants.read(self.long_operand(offset))
+    }
+
+    /// Reads the 24-bit big-endian operand a long instruction stores at `offset`.
+    fn long_operand(&self, offset: usize) -> usize {
+        (self.code[offset] as usize) << 16 | (self.code[offset + 1] as usize) << 8 | self.code[offset + 2] as usize
+    }
+
+    /// Reads the string literal id operand of the instruction at `offset`, which is one byte
+    /// for the short forms and 24 bits for `StringLiteralLong` and the long global ops.
+    pub fn read_literal_operand(&self, offset: usize) -> StringId {
+        match OpCode::from_u8(self.code[offset]) {
+            OpCode::StringLiteralLong |
+            OpCode::GetGlobalLong | OpCode::DefineGlobalLong | OpCode::SetGlobalLong | OpCode::DeleteGlobalLong => {
+                StringId::new_long_literal_id(self.long_operand(offset + 1))
+            },
+            _ => StringId::new_literal_id(self.code[offset + 1]),
+        }
+    }
+
     pub fn read_string_literal(&self, literal: &StringId) -> &str {
         self.string_literals.get_string(literal)
     }
 
-    pub fn get_line(&self, offset: usize) -> u32 {
-        self.lines[offset]
+    /// Source line of the instruction at `offset`, which can also point into its operands.
+    /// This is the API to use for mapping executed instructions back to lines, as in profilers
+    /// and error reports; it stays correct however lines are stored internally.
+    pub fn line_at_instruction(&self, offset: usize) -> u32 {
+        let run = self.lines.partition_point(|run| run.start <= offset);
+        self.lines[run - 1].line
+    }
+
+    /// Keeps a copy of `source` for rendering spans and returns its index for `Span::source`.
+    pub fn add_source(&mut self, source: &str) -> usize {
+        self.sources.push(String::from(source));
+        self.sources.len() - 1
+    }
+
+    pub fn set_span(&mut self, offset: usize, span: Span) {
+        self.spans[offset] = Some(span);
+    }
+
+    pub fn get_span(&self, offset: usize) -> Option<Span> {
+        self.spans[offset]
+    }
+
+    /// Renders the source line of the instruction at `offset` with `^` under its span:
+    ///
+    /// ```text
+    ///  3 | print a + "b";
+    ///    |         ^
+    /// ```
+    ///
+    /// Returns `None` when the instruction has no span.
+    pub fn render_span(&self, offset: usize) -> Option<String> {
+        let span = self.spans[offset]?;
+        let source = &self.sources[span.source];
+
+        let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[span.start..].find('\n').map_or(source.len(), |i| span.start + i);
+        let text = source[line_start..line_end].trim_end_matches('\r');
+
+        // Tabs are kept so the carets line up under tab-indented code.
+        let padding: String = source[line_start..span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        // A span running past the end of its line is underlined up to the line end.
+        let end = (span.start + span.length).min(line_end);
+        let width = source[span.start..end].chars().count().max(1);
+
+        let number = span.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        Some(format!(" {} | {}\n {} | {}{}", number, text, gutter, padding, "^".repeat(width)))
+    }
+
+    /// Checks that every instruction is a known opcode with all of its operand bytes,
+    /// that constant and string literal indices exist, and that jumps and function
+    /// entries land on instruction boundaries. Malformed chunks fail here instead of
+    /// panicking in the VM.
+    pub fn verify(&self) -> Result<(), String> {
+        let mut offset = 0;
+        let mut boundaries = vec![false; self.code.len()];
+        // (instruction offset, target) pairs, checked once every boundary is known.
+        let mut targets = Vec::new();
+
+        while offset < self.code.len() {
+            let opcode = match OpCode::try_from_u8(self.code[offset]) {
+                Some(opcode) => opcode,
+                None => return Err(format!("Unknown opcode {} at {:04}", self.code[offset], offset)),
+            };
+
+            let operands = operand_count(opcode);
+            self.check_operands(opcode, offset, operands)?;
+            boundaries[offset] = true;
+
+            match opcode {
+                OpCode::StringLiteral |
+                OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal | OpCode::DeleteGlobal |
+                OpCode::GetProperty | OpCode::SetProperty | OpCode::GetSuper |
+                OpCode::Invoke | OpCode::SuperInvoke | OpCode::Class | OpCode::Method => {
+                    let id = self.code[offset + 1] as usize;
+                    if id >= self.string_literals.count() {
+                        return Err(format!("{} at {:04} uses missing string literal {}", opcode, offset, id));
+                    }
+                },
+                OpCode::StringLiteralLong |
+                OpCode::GetGlobalLong | OpCode::DefineGlobalLong | OpCode::SetGlobalLong | OpCode::DeleteGlobalLong => {
+                    let id = self.long_operand(offset + 1);
+                    if id >= self.string_literals.count() {
+                        return Err(format!("{} at {:04} uses missing string literal {}", opcode, offset, id));
+                    }
+                },
+                OpCode::IterNext => {
+                    let jump = (self.code[offset + 2] as usize) << 8 | self.code[offset + 3] as usize;
+                    targets.push((offset, offset + 4 + jump));
+                },
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfNotNil | OpCode::PopJumpIfFalse | OpCode::Loop | OpCode::PushHandler => {
+                    let jump = (self.code[offset + 1] as usize) << 8 | self.code[offset + 2] as usize;
+                    let target = if opcode == OpCode::Loop {
+                        (offset + 3).checked_sub(jump)
+                    } else {
+                        Some(offset + 3 + jump)
+                    };
+                    match target {
+                        Some(target) => targets.push((offset, target)),
+                        None => return Err(format!("{} at {:04} jumps before the start of the chunk", opcode, offset)),
+                    }
+                },
+                _ => {},
+            }
+
+            let mut next = offset + 1 + operands;
+            if let OpCode::Constant | OpCode::ConstantLong | OpCode::Closure = opcode {
+                let idx = if opcode == OpCode::ConstantLong {
+                    self.long_operand(offset + 1)
+                } else {
+                    self.code[offset + 1] as usize
+                };
+                let value = match self.constants.values.get(idx) {
+                    Some(value) => value,
+                    None => return Err(format!("{} at {:04} uses missing constant {}", opcode, offset, idx)),
+                };
+
+                if opcode == OpCode::Closure {
+                    let function = match value {
+                        Value::Function(function) => function,
+                        _ => return Err(format!("{} at {:04} needs a function constant", opcode, offset)),
+                    };
+                    self.check_operands(opcode, offset, operands + 2 * function.upvalue_count)?;
+                    next += 2 * function.upvalue_count;
+                    targets.push((offset, function.entry));
+                }
+            }
+
+            offset = next;
+        }
+
+        for (offset, target) in targets {
+            if !boundaries.get(target).copied().unwrap_or(false) {
+                let opcode = OpCode::from_u8(self.code[offset]);
+                return Err(format!("{} at {:04} targets {:04}, which is not an instruction", opcode, offset, target));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Globals that code from `from` on reads or assigns but never defines, each with the offset
+    /// of its first use. Expects a chunk that passed `verify`.
+    pub fn undefined_globals(&self, from: usize) -> Vec<(StringId, usize)> {
+        let mut defined = HashSet::new();
+        let mut used: Vec<(StringId, usize)> = Vec::new();
+
+        let mut offset = from;
+        while offset < self.code.len() {
+            let opcode = OpCode::from_u8(self.code[offset]);
+            let mut next = offset + 1 + operand_count(opcode);
+
+            match opcode {
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    defined.insert(self.read_literal_operand(offset));
+                },
+                OpCode::GetGlobal | OpCode::SetGlobal | OpCode::GetGlobalLong | OpCode::SetGlobalLong => {
+                    let id = self.read_literal_operand(offset);
+                    if !used.iter().any(|(used_id, _)| *used_id == id) {
+                        used.push((id, offset));
+                    }
+                },
+                OpCode::Closure => {
+                    if let Value::Function(function) = self.read_constant(offset + 1) {
+                        next += 2 * function.upvalue_count;
+                    }
+                },
+                _ => {},
+            }
+
+            offset = next;
+        }
+
+        used.retain(|(id, _)| !defined.contains(id));
+        used
+    }
+
+    /// How much the instructions in `from..to` grow the stack when run straight through.
+    /// Jumps are ignored, so this only holds for code where every path pushes and pops
+    /// the same, like a single expression.
+    pub fn stack_effect(&self, from: usize, to: usize) -> isize {
+        let mut effect = 0;
+        let mut offset = from;
+        while offset < to {
+            let opcode = OpCode::from_u8(self.code[offset]);
+            let operand = |index: usize| self.code[offset + index] as isize;
+            effect += match opcode {
+                OpCode::Constant | OpCode::ConstantLong | OpCode::Int |
+                OpCode::StringLiteral | OpCode::StringLiteralLong |
+                OpCode::Nil | OpCode::True | OpCode::False |
+                OpCode::GetLocal | OpCode::GetUpvalue | OpCode::Closure | OpCode::Class | OpCode::IterNext => 1,
+                OpCode::Pop | OpCode::SetGlobal | OpCode::SetGlobalLong | OpCode::DeleteGlobal | OpCode::DeleteGlobalLong |
+                OpCode::SetProperty | OpCode::GetSuper |
+                OpCode::Equal | OpCode::Greater | OpCode::Less |
+                OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide |
+                OpCode::Print | OpCode::EPrint | OpCode::PrintElement | OpCode::Throw |
+                OpCode::PopJumpIfFalse | OpCode::CloseUpvalue | OpCode::Inherit | OpCode::Method |
+                OpCode::GetIndex => -1,
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong | OpCode::SetIndex => -2,
+                // The callee or receiver is replaced by the result.
+                OpCode::Call => -operand(1),
+                OpCode::Invoke => -operand(2),
+                // The superclass is popped as well.
+                OpCode::SuperInvoke => -operand(2) - 1,
+                OpCode::BuildList => 1 - operand(1),
+                OpCode::BuildMap => 1 - 2 * operand(1),
+                OpCode::Unpack => operand(1) - 1,
+                _ => 0,
+            };
+
+            offset += 1 + operand_count(opcode);
+            if opcode == OpCode::Closure {
+                if let Value::Function(function) = self.read_constant(offset - 1) {
+                    offset += 2 * function.upvalue_count;
+                }
+            }
+        }
+
+        effect
+    }
+
+    fn check_operands(&self, opcode: OpCode, offset: usize, operands: usize) -> Result<(), String> {
+        if offset + operands >= self.code.len() {
+            return Err(format!("{} at {:04} is missing operand bytes", opcode, offset));
+        }
+
+        Ok(())
     }
 
     pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+        print!("{}", self.disassemble_to_string(name));
+    }
+
+    pub fn disassemble_to_string(&self, name: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "== {} ==", name).unwrap();
 
         let mut offset = 0;
 
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.write_instruction(&mut out, offset);
         }
+
+        out
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
+    /// Appends the instruction at `offset` to `out` and returns the offset of the next one.
+    pub fn disassemble_instruction(&self, out: &mut String, offset: usize) -> usize {
+        self.write_instruction(out, offset)
+    }
+
+    fn write_instruction(&self, out: &mut String, offset: usize) -> usize {
+        write!(out, "{:04} ", offset).unwrap();
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+        let line = self.line_at_instruction(offset);
+        if offset > 0 && line == self.line_at_instruction(offset - 1) {
+            write!(out, "   | ").unwrap();
         } else {
-            print!("{:4} ", self.lines[offset]);
+            write!(out, "{:4} ", line).unwrap();
         }
 
         let code = OpCode::from_u8(self.code[offset]);
 
         match code {
-            OpCode::Constant => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::StringLiteral => self.string_literal_instruction("OP_STRING_LITERAL", offset),
-            OpCode::Nil => self.simple_instruction("OP_NIL", offset),
-            OpCode::True => self.simple_instruction("OP_TRUE", offset),
-            OpCode::False => self.simple_instruction("OP_FALSE", offset),
-            OpCode::Pop => self.simple_instruction("OP_POP", offset),
-            OpCode::GetGlobal => self.global_instruction("OP_GET_GLOBAL", offset),
-            OpCode::DefineGlobal => self.global_instruction("OP_DEFINE_GLOBAL", offset),
-            OpCode::SetGlobal => self.global_instruction("OP_SET_GLOBAL", offset),
-            OpCode::Equal => self.simple_instruction("OP_EQUAL", offset),
-            OpCode::Greater => self.simple_instruction("OP_GREATER", offset),
-            OpCode::Less => self.simple_instruction("OP_LESS", offset),
-            OpCode::Add => self.simple_instruction("OP_ADD", offset),
-            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT", offset),
-            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY", offset),
-            OpCode::Divide => self.simple_instruction("OP_DIVIDE", offset),
-            OpCode::Not => self.simple_instruction("OP_NOT", offset),
-            OpCode::Negate => self.simple_instruction("OP_NEGATE", offset),
-            OpCode::Print => self.simple_instruction("OP_PRINT", offset),
-            OpCode::Return => self.simple_instruction("OP_RETURN", offset),
-        }
-    }
-
-    pub fn print_codes(&self) {
-        for (_, code) in self.code.iter().enumerate() {
-            let code = OpCode::from_u8(*code);
-            println!("{}", code);
-        }
-    }
-
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+            OpCode::Constant => self.constant_instruction(out, "OP_CONSTANT", offset),
+            OpCode::ConstantLong => self.constant_long_instruction(out, "OP_CONSTANT_LONG", offset),
+            OpCode::Int => self.byte_instruction(out, "OP_INT", offset),
+            OpCode::StringLiteral => self.string_literal_instruction(out, "OP_STRING_LITERAL", offset),
+            OpCode::StringLiteralLong => self.literal_long_instruction(out, "OP_STRING_LITERAL_LONG", offset),
+            OpCode::Nil => self.simple_instruction(out, "OP_NIL", offset),
+            OpCode::True => self.simple_instruction(out, "OP_TRUE", offset),
+            OpCode::False => self.simple_instruction(out, "OP_FALSE", offset),
+            OpCode::Pop => self.simple_instruction(out, "OP_POP", offset),
+            OpCode::GetLocal => self.byte_instruction(out, "OP_GET_LOCAL", offset),
+            OpCode::SetLocal => self.byte_instruction(out, "OP_SET_LOCAL", offset),
+            OpCode::GetGlobal => self.global_instruction(out, "OP_GET_GLOBAL", offset),
+            OpCode::GetGlobalLong => self.literal_long_instruction(out, "OP_GET_GLOBAL_LONG", offset),
+            OpCode::DefineGlobal => self.global_instruction(out, "OP_DEFINE_GLOBAL", offset),
+            OpCode::DefineGlobalLong => self.literal_long_instruction(out, "OP_DEFINE_GLOBAL_LONG", offset),
+            OpCode::SetGlobal => self.global_instruction(out, "OP_SET_GLOBAL", offset),
+            OpCode::SetGlobalLong => self.literal_long_instruction(out, "OP_SET_GLOBAL_LONG", offset),
+            OpCode::DeleteGlobal => self.global_instruction(out, "OP_DELETE_GLOBAL", offset),
+            OpCode::DeleteGlobalLong => self.literal_long_instruction(out, "OP_DELETE_GLOBAL_LONG", offset),
+            OpCode::GetUpvalue => self.byte_instruction(out, "OP_GET_UPVALUE", offset),
+            OpCode::SetUpvalue => self.byte_instruction(out, "OP_SET_UPVALUE", offset),
+            OpCode::GetProperty => self.global_instruction(out, "OP_GET_PROPERTY", offset),
+            OpCode::SetProperty => self.global_instruction(out, "OP_SET_PROPERTY", offset),
+            OpCode::GetSuper => self.global_instruction(out, "OP_GET_SUPER", offset),
+            OpCode::Equal => self.simple_instruction(out, "OP_EQUAL", offset),
+            OpCode::Greater => self.simple_instruction(out, "OP_GREATER", offset),
+            OpCode::Less => self.simple_instruction(out, "OP_LESS", offset),
+            OpCode::Add => self.simple_instruction(out, "OP_ADD", offset),
+            OpCode::Subtract => self.simple_instruction(out, "OP_SUBTRACT", offset),
+            OpCode::Multiply => self.simple_instruction(out, "OP_MULTIPLY", offset),
+            OpCode::Divide => self.simple_instruction(out, "OP_DIVIDE", offset),
+            OpCode::Not => self.simple_instruction(out, "OP_NOT", offset),
+            OpCode::Negate => self.simple_instruction(out, "OP_NEGATE", offset),
+            OpCode::TypeOf => self.simple_instruction(out, "OP_TYPEOF", offset),
+            OpCode::Print => self.simple_instruction(out, "OP_PRINT", offset),
+            OpCode::EPrint => self.simple_instruction(out, "OP_EPRINT", offset),
+            OpCode::PrintElement => self.simple_instruction(out, "OP_PRINT_ELEMENT", offset),
+            OpCode::Throw => self.simple_instruction(out, "OP_THROW", offset),
+            OpCode::Jump => self.jump_instruction(out, "OP_JUMP", 1, offset),
+            OpCode::JumpIfFalse => self.jump_instruction(out, "OP_JUMP_IF_FALSE", 1, offset),
+            OpCode::JumpIfNotNil => self.jump_instruction(out, "OP_JUMP_IF_NOT_NIL", 1, offset),
+            OpCode::PopJumpIfFalse => self.jump_instruction(out, "OP_POP_JUMP_IF_FALSE", 1, offset),
+            OpCode::Loop => self.jump_instruction(out, "OP_LOOP", -1, offset),
+            OpCode::Call => self.byte_instruction(out, "OP_CALL", offset),
+            OpCode::Invoke => self.invoke_instruction(out, "OP_INVOKE", offset),
+            OpCode::SuperInvoke => self.invoke_instruction(out, "OP_SUPER_INVOKE", offset),
+            OpCode::Closure => self.closure_instruction(out, "OP_CLOSURE", offset),
+            OpCode::CloseUpvalue => self.simple_instruction(out, "OP_CLOSE_UPVALUE", offset),
+            OpCode::Return => self.simple_instruction(out, "OP_RETURN", offset),
+            OpCode::Class => self.global_instruction(out, "OP_CLASS", offset),
+            OpCode::Inherit => self.simple_instruction(out, "OP_INHERIT", offset),
+            OpCode::Method => self.global_instruction(out, "OP_METHOD", offset),
+            OpCode::BuildMap => self.byte_instruction(out, "OP_BUILD_MAP", offset),
+            OpCode::BuildList => self.byte_instruction(out, "OP_BUILD_LIST", offset),
+            OpCode::GetIndex => self.simple_instruction(out, "OP_GET_INDEX", offset),
+            OpCode::SetIndex => self.simple_instruction(out, "OP_SET_INDEX", offset),
+            OpCode::Unpack => self.byte_instruction(out, "OP_UNPACK", offset),
+            OpCode::GetIter => self.simple_instruction(out, "OP_GET_ITER", offset),
+            OpCode::IterNext => self.iter_next_instruction(out, "OP_ITER_NEXT", offset),
+            OpCode::PushHandler => self.jump_instruction(out, "OP_PUSH_HANDLER", 1, offset),
+            OpCode::PopHandler => self.simple_instruction(out, "OP_POP_HANDLER", offset),
+        }
+    }
+
+    fn constant_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
         let value_idx = self.code[offset + 1];
-        println!("{:16} {:4} '{}'", name, value_idx, self.constants.read(value_idx as usize));
+        writeln!(out, "{:16} {:4} {}", name, value_idx, self.describe_constant(value_idx as usize)).unwrap();
+        offset + 2
+    }
+
+    fn constant_long_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let value_idx = self.long_operand(offset + 1);
+        writeln!(out, "{:16} {:4} {}", name, value_idx, self.describe_constant(value_idx)).unwrap();
+        offset + 4
+    }
+
+    fn global_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let literal_idx = self.code[offset + 1];
+        writeln!(out, "{:16} {:4} {}", name, literal_idx, self.describe_string_literal(literal_idx as usize)).unwrap();
         offset + 2
     }
 
-    fn global_instruction(&self, name: &str, offset: usize) -> usize {
+    fn string_literal_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
         let literal_idx = self.code[offset + 1];
-        println!("{:16} {:4} '{}'", name, literal_idx, self.string_literals.get_string(&StringId::new_literal_id(literal_idx)));
+        writeln!(out, "{:16} {:4} {}", name, literal_idx, self.describe_string_literal(literal_idx as usize)).unwrap();
         offset + 2
     }
 
-    fn string_literal_instruction(&self, name: &str, offset: usize) -> usize {
+    fn literal_long_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let literal_idx = self.long_operand(offset + 1);
+        writeln!(out, "{:16} {:4} {}", name, literal_idx, self.describe_string_literal(literal_idx)).unwrap();
+        offset + 4
+    }
+
+    fn invoke_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
         let literal_idx = self.code[offset + 1];
-        println!("{:16} {:4} '{}'", name, literal_idx, self.string_literals.get_string(&StringId::new_literal_id(literal_idx)));
+        let arg_count = self.code[offset + 2];
+        writeln!(out, "{:16} ({} args) {:4} {}", name, arg_count, literal_idx, self.describe_string_literal(literal_idx as usize)).unwrap();
+        offset + 3
+    }
+
+    fn closure_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let value_idx = self.code[offset + 1];
+        writeln!(out, "{:16} {:4} {}", name, value_idx, self.describe_constant(value_idx as usize)).unwrap();
+
+        let upvalue_count = match self.constants.values.get(value_idx as usize) {
+            Some(Value::Function(function)) => function.upvalue_count,
+            _ => 0,
+        };
+
+        let mut offset = offset + 2;
+        for _ in 0..upvalue_count {
+            let is_local = self.code[offset];
+            let index = self.code[offset + 1];
+            writeln!(out, "{:04}    |                     {} {}", offset, if is_local == 1 { "local" } else { "upvalue" }, index).unwrap();
+            offset += 2;
+        }
+
+        offset
+    }
+
+    /// The constant at `idx` in quotes, or `<invalid idx>` when a bad chunk points past the pool.
+    fn describe_constant(&self, idx: usize) -> String {
+        match self.constants.values.get(idx) {
+            Some(Value::String(id)) => match self.string_literals.try_get_string(id) {
+                Some(string) => format!("'{}'", string),
+                None => format!("<invalid string id {}>", id.0),
+            },
+            Some(value) => format!("'{}'", value),
+            None => format!("<invalid {}>", idx),
+        }
+    }
+
+    /// Like `describe_constant`, for string literal ids.
+    fn describe_string_literal(&self, idx: usize) -> String {
+        match self.string_literals.try_get_string(&StringId::new_long_literal_id(idx)) {
+            Some(string) => format!("'{}'", string),
+            None => format!("<invalid {}>", idx),
+        }
+    }
+
+    fn byte_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let slot = self.code[offset + 1];
+        writeln!(out, "{:16} {:4}", name, slot).unwrap();
         offset + 2
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{}", name);
+    /// Prints a jump with the offset it lands on. `sign` is -1 for backward jumps.
+    fn jump_instruction(&self, out: &mut String, name: &str, sign: i64, offset: usize) -> usize {
+        let jump = (self.code[offset + 1] as u16) << 8 | self.code[offset + 2] as u16;
+        let target = offset as i64 + 3 + sign * jump as i64;
+        writeln!(out, "{:16} {:04} -> {:04}", name, offset, target).unwrap();
+        offset + 3
+    }
+
+    /// Prints the iterable's local slot and where the loop exits to.
+    fn iter_next_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let slot = self.code[offset + 1];
+        let jump = (self.code[offset + 2] as usize) << 8 | self.code[offset + 3] as usize;
+        writeln!(out, "{:16} {:4} -> {:04}", name, slot, offset + 4 + jump).unwrap();
+        offset + 4
+    }
+
+    fn simple_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        writeln!(out, "{}", name).unwrap();
         offset + 1
     }
 }
+
+/// Assembles a `Chunk` by hand, to exercise the VM one opcode at a time without the compiler.
+///
+/// ```
+/// use clox_rs::lox::chunk::{ChunkBuilder, OpCode};
+/// use clox_rs::lox::value::Value;
+///
+/// let chunk = ChunkBuilder::new()
+///     .constant(Value::Number(1.0))
+///     .constant(Value::Number(2.0))
+///     .op(OpCode::Add)
+///     .op(OpCode::Print)
+///     .op(OpCode::Nil)
+///     .op(OpCode::Return)
+///     .build();
+///
+/// assert_eq!(chunk.verify(), Ok(()));
+/// ```
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    line: u32,
+}
+
+impl ChunkBuilder {
+    pub fn new() -> ChunkBuilder {
+        ChunkBuilder {
+            chunk: Chunk::new(),
+            line: 1,
+        }
+    }
+
+    /// Sets the line reported for the code written after this.
+    pub fn line(mut self, line: u32) -> ChunkBuilder {
+        self.line = line;
+        self
+    }
+
+    pub fn op(mut self, opcode: OpCode) -> ChunkBuilder {
+        self.chunk.write(opcode, self.line);
+        self
+    }
+
+    /// Writes a raw operand byte, like a local slot or an argument count.
+    pub fn byte(mut self, v: u8) -> ChunkBuilder {
+        self.chunk.write_u8(v, self.line);
+        self
+    }
+
+    /// Writes the instruction loading `value`. Panics when the constant pool is full.
+    pub fn constant(mut self, value: Value) -> ChunkBuilder {
+        self.chunk.write_constant(value, self.line).expect("Failed to add constant");
+        self
+    }
+
+    /// Writes a `StringLiteral` pushing `string`. Panics when there are too many literals.
+    pub fn string(mut self, string: &str) -> ChunkBuilder {
+        self.chunk.emit_string(string, self.line).expect("Failed to add string literal");
+        self
+    }
+
+    /// Writes `opcode` with the literal id of `name` as its operand, for global and property ops.
+    /// Global ops switch to their long form when the id needs it; other ops panic on such ids.
+    pub fn named(mut self, opcode: OpCode, name: &str) -> ChunkBuilder {
+        let id = self.chunk.add_or_retrieve_string_literal(name).expect("Failed to add string literal");
+        match opcode {
+            OpCode::GetGlobal | OpCode::SetGlobal | OpCode::DefineGlobal | OpCode::DeleteGlobal => {
+                self.chunk.write_global(opcode, &id, self.line).expect("Failed to write global");
+            },
+            _ => {
+                self.chunk.write(opcode, self.line);
+                self.chunk.write_string_literal_id(&id, self.line).expect("Failed to write string literal id");
+            },
+        }
+        self
+    }
+
+    pub fn build(self) -> Chunk {
+        self.chunk
+    }
+}
+
+impl Default for ChunkBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Number of operand bytes after `opcode`, not counting a closure's upvalue pairs.
+pub fn operand_count(opcode: OpCode) -> usize {
+    match opcode {
+        OpCode::Constant | OpCode::Int | OpCode::StringLiteral |
+        OpCode::GetLocal | OpCode::SetLocal |
+        OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal | OpCode::DeleteGlobal |
+        OpCode::GetUpvalue | OpCode::SetUpvalue |
+        OpCode::GetProperty | OpCode::SetProperty | OpCode::GetSuper |
+        OpCode::Call | OpCode::Closure | OpCode::Class | OpCode::Method |
+        OpCode::BuildList | OpCode::BuildMap | OpCode::Unpack => 1,
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfNotNil | OpCode::PopJumpIfFalse | OpCode::Loop |
+        OpCode::Invoke | OpCode::SuperInvoke | OpCode::PushHandler => 2,
+        OpCode::ConstantLong | OpCode::IterNext |
+        OpCode::StringLiteralLong |
+        OpCode::GetGlobalLong | OpCode::DefineGlobalLong | OpCode::SetGlobalLong | OpCode::DeleteGlobalLong => 3,
+        _ => 0,
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bytes every serialized chunk starts with.
+const HEADER_MAGIC: &[u8; 4] = b"LOXC";
+
+/// Appends the header of a serialized chunk to `out`: the magic bytes, then the length and bytes
+/// of `version`, the crate version of the build writing it.
+pub fn write_header(out: &mut Vec<u8>, version: &str) {
+    out.extend_from_slice(HEADER_MAGIC);
+    out.push(version.len() as u8);
+    out.extend_from_slice(version.as_bytes());
+}
+
+/// Checks the header at the start of `bytes` and returns the offset the chunk data starts at.
+/// The bytecode can change between versions, so chunks written by another one are rejected.
+pub fn read_header(bytes: &[u8], expected_version: &str) -> Result<usize, String> {
+    if !bytes.starts_with(HEADER_MAGIC) {
+        return Err(String::from("Not a serialized chunk"));
+    }
+
+    let length = match bytes.get(HEADER_MAGIC.len()) {
+        Some(&length) => length as usize,
+        None => return Err(String::from("Chunk header is truncated")),
+    };
+    let start = HEADER_MAGIC.len() + 1;
+    let version = match bytes.get(start..start + length) {
+        Some(version) => String::from_utf8_lossy(version),
+        None => return Err(String::from("Chunk header is truncated")),
+    };
+
+    if version != expected_version {
+        return Err(format!("Chunk was written by clox-rs {}, but this is clox-rs {}", version, expected_version));
+    }
+
+    Ok(start + length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembly_shows_bad_string_constants() {
+        let mut chunk = Chunk::new();
+        // `add_constant` turns away unknown ids, so put one in the pool the way a corrupted chunk would.
+        chunk.constants.write(Value::String(StringId::new_literal_id(7)));
+        chunk.write(OpCode::Constant, 1);
+        chunk.write_u8(0, 1);
+        chunk.write(OpCode::Constant, 1);
+        chunk.write_u8(3, 1);
+
+        let listing = chunk.disassemble_to_string("bad");
+        assert_eq!(listing.lines().nth(1), Some("0000    1 OP_CONSTANT         0 <invalid string id 7>"));
+        assert_eq!(listing.lines().nth(2), Some("0002    | OP_CONSTANT         3 <invalid 3>"));
+    }
+}
@@ -1,12 +1,18 @@
 use crate::lox::scanner::{TokenType, Token, ScannerPointer, scan_token};
-use crate::lox::chunk::{OpCode, Chunk};
+use crate::lox::chunk::{OpCode, Chunk, Span};
 use crate::lox::value::Value;
-use crate::lox::object::StringId;
+use crate::lox::object::{StringId, Function};
+
+use core::fmt::Display;
+use std::fs::{canonicalize, read_to_string};
+use std::path::PathBuf;
 
 #[derive(PartialEq, PartialOrd)]
 enum Precedence {
     None,
+    Comma,      // ,
     Assignment, // =
+    Coalesce,   // ??
     Or,         // or
     And,        // and
     Equality,   // == !=
@@ -21,8 +27,10 @@ enum Precedence {
 impl Precedence {
     fn next_higher_precedence(precedence: &Precedence) -> Precedence {
         match precedence {
-            Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
+            Precedence::None => Precedence::Comma,
+            Precedence::Comma => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Coalesce,
+            Precedence::Coalesce => Precedence::Or,
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
@@ -36,7 +44,9 @@ impl Precedence {
     }
 }
 
-type ParseFn = fn(&mut Chunk, &String, &mut CompilerContext);
+type ParseFn = fn(&mut Chunk, &str, &mut CompilerContext);
+
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
 
 struct ParseRule {
     prefix: Option<ParseFn>,
@@ -45,7 +55,7 @@ struct ParseRule {
 }
 
 impl ParseRule {
-    fn new(prefix: Option<ParseFn>, infix: Option<ParseFn>, precedence: Precedence) -> ParseRule {
+    const fn new(prefix: Option<ParseFn>, infix: Option<ParseFn>, precedence: Precedence) -> ParseRule {
         ParseRule {
             prefix,
             infix,
@@ -53,58 +63,97 @@ impl ParseRule {
         }
     }
 
-    fn query(token_type: TokenType) -> ParseRule {
-        match token_type {
-            TokenType::LeftParen => ParseRule::new(Some(grouping), None, Precedence::None),
-            TokenType::RightParen => ParseRule::new(None, None, Precedence::None),
-            TokenType::LeftBrace => ParseRule::new(None, None, Precedence::None),
-            TokenType::RightBrace => ParseRule::new(None, None, Precedence::None),
-            TokenType::Comma => ParseRule::new(None, None, Precedence::None),
-            TokenType::Dot => ParseRule::new(None, None, Precedence::None),
-            TokenType::Minus => ParseRule::new(Some(unary), Some(binary), Precedence::Term),
-            TokenType::Plus => ParseRule::new(None, Some(binary), Precedence::Term),
-            TokenType::Semicolon => ParseRule::new(None, None, Precedence::None),
-            TokenType::Slash => ParseRule::new(None, Some(binary), Precedence::Factor),
-            TokenType::Star => ParseRule::new(None, Some(binary), Precedence::Factor),
-            TokenType::Bang => ParseRule::new(Some(unary), None, Precedence::None),
-            TokenType::BangEqual => ParseRule::new(None, Some(binary), Precedence::Equality),
-            TokenType::Equal => ParseRule::new(None, None, Precedence::None),
-            TokenType::EqualEqual => ParseRule::new(None, Some(binary), Precedence::Equality),
-            TokenType::Greater => ParseRule::new(None, Some(binary), Precedence::Comparison),
-            TokenType::GreaterEqual => ParseRule::new(None, Some(binary), Precedence::Comparison),
-            TokenType::Less => ParseRule::new(None, Some(binary), Precedence::Comparison),
-            TokenType::LessEqual => ParseRule::new(None, Some(binary), Precedence::Comparison),
-            TokenType::Identifier => ParseRule::new(Some(variable), None, Precedence::None),
-            TokenType::String => ParseRule::new(Some(string), None, Precedence::None),
-            TokenType::Number => ParseRule::new(Some(number), None, Precedence::None),
-            TokenType::And => ParseRule::new(None, None, Precedence::None),
-            TokenType::Class => ParseRule::new(None, None, Precedence::None),
-            TokenType::Else => ParseRule::new(None, None, Precedence::None),
-            TokenType::False => ParseRule::new(Some(literal), None, Precedence::None),
-            TokenType::Fun => ParseRule::new(None, None, Precedence::None),
-            TokenType::For => ParseRule::new(None, None, Precedence::None),
-            TokenType::If => ParseRule::new(None, None, Precedence::None),
-            TokenType::Nil => ParseRule::new(Some(literal), None, Precedence::None),
-            TokenType::Or => ParseRule::new(None, None, Precedence::None),
-            TokenType::Print => ParseRule::new(None, None, Precedence::None),
-            TokenType::Return => ParseRule::new(None, None, Precedence::None),
-            TokenType::Super => ParseRule::new(None, None, Precedence::None),
-            TokenType::This => ParseRule::new(None, None, Precedence::None),
-            TokenType::True => ParseRule::new(Some(literal), None, Precedence::None),
-            TokenType::Var => ParseRule::new(None, None, Precedence::None),
-            TokenType::While => ParseRule::new(None, None, Precedence::None),
-            TokenType::Error => ParseRule::new(None, None, Precedence::None),
-            TokenType::EOF => ParseRule::new(None, None, Precedence::None),
-        }
+    fn query(token_type: TokenType) -> &'static ParseRule {
+        &RULES[token_type as usize]
     }
 }
 
+// `EOF` is the last token type, so this covers every one of them.
+const TOKEN_TYPE_COUNT: usize = TokenType::EOF as usize + 1;
+
+/// Parse rules indexed by token type, built once at compile time.
+static RULES: [ParseRule; TOKEN_TYPE_COUNT] = {
+    const EMPTY: ParseRule = ParseRule::new(None, None, Precedence::None);
+    let mut rules = [EMPTY; TOKEN_TYPE_COUNT];
+    rules[TokenType::LeftParen as usize] = ParseRule::new(Some(grouping), Some(call), Precedence::Call);
+    rules[TokenType::RightParen as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::LeftBrace as usize] = ParseRule::new(Some(brace), None, Precedence::None);
+    rules[TokenType::RightBrace as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::LeftBracket as usize] = ParseRule::new(Some(list_literal), Some(subscript), Precedence::Call);
+    rules[TokenType::RightBracket as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Colon as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Comma as usize] = ParseRule::new(None, Some(comma), Precedence::Comma);
+    rules[TokenType::Dot as usize] = ParseRule::new(None, Some(dot), Precedence::Call);
+    rules[TokenType::Minus as usize] = ParseRule::new(Some(unary), Some(binary), Precedence::Term);
+    rules[TokenType::Plus as usize] = ParseRule::new(None, Some(binary), Precedence::Term);
+    rules[TokenType::Semicolon as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Slash as usize] = ParseRule::new(None, Some(binary), Precedence::Factor);
+    rules[TokenType::Star as usize] = ParseRule::new(None, Some(binary), Precedence::Factor);
+    rules[TokenType::Bang as usize] = ParseRule::new(Some(unary), None, Precedence::None);
+    rules[TokenType::BangEqual as usize] = ParseRule::new(None, Some(binary), Precedence::Equality);
+    rules[TokenType::Equal as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::EqualEqual as usize] = ParseRule::new(None, Some(binary), Precedence::Equality);
+    rules[TokenType::Greater as usize] = ParseRule::new(None, Some(binary), Precedence::Comparison);
+    rules[TokenType::GreaterEqual as usize] = ParseRule::new(None, Some(binary), Precedence::Comparison);
+    rules[TokenType::Less as usize] = ParseRule::new(None, Some(binary), Precedence::Comparison);
+    rules[TokenType::LessEqual as usize] = ParseRule::new(None, Some(binary), Precedence::Comparison);
+    rules[TokenType::Identifier as usize] = ParseRule::new(Some(variable), None, Precedence::None);
+    rules[TokenType::String as usize] = ParseRule::new(Some(string), None, Precedence::None);
+    rules[TokenType::Number as usize] = ParseRule::new(Some(number), None, Precedence::None);
+    rules[TokenType::And as usize] = ParseRule::new(None, Some(and_), Precedence::And);
+    rules[TokenType::Class as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Del as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Else as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::False as usize] = ParseRule::new(Some(literal), None, Precedence::None);
+    rules[TokenType::Fun as usize] = ParseRule::new(Some(lambda), None, Precedence::None);
+    rules[TokenType::For as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::If as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Import as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::In as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Nil as usize] = ParseRule::new(Some(literal), None, Precedence::None);
+    rules[TokenType::MinusMinus as usize] = ParseRule::new(None, Some(invalid_increment), Precedence::Call);
+    rules[TokenType::PlusPlus as usize] = ParseRule::new(None, Some(invalid_increment), Precedence::Call);
+    rules[TokenType::QuestionQuestion as usize] = ParseRule::new(None, Some(coalesce), Precedence::Coalesce);
+    rules[TokenType::Or as usize] = ParseRule::new(None, Some(or_), Precedence::Or);
+    rules[TokenType::Print as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::EPrint as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Return as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Super as usize] = ParseRule::new(Some(super_), None, Precedence::None);
+    rules[TokenType::This as usize] = ParseRule::new(Some(this), None, Precedence::None);
+    rules[TokenType::Throw as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Break as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Continue as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Do as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Try as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Catch as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::True as usize] = ParseRule::new(Some(literal), None, Precedence::None);
+    rules[TokenType::TypeOf as usize] = ParseRule::new(Some(unary), None, Precedence::None);
+    rules[TokenType::Var as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::While as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Comment as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::Error as usize] = ParseRule::new(None, None, Precedence::None);
+    rules[TokenType::EOF as usize] = ParseRule::new(None, None, Precedence::None);
+    rules
+};
+
 struct CompilerContext {
     sp: ScannerPointer,
     pp: ParserPointer,
     ps: ParserState,
+    fc: FunctionCompiler,
+    classes: Vec<ClassCompiler>,
     can_assign: bool,
+    /// The scanner's running line counter. It can be past the token being parsed,
+    /// so code and errors take their line from a token instead.
     line: u32,
+    options: CompileOptions,
+    /// Code offset right after the last assignment, if nothing has been emitted since.
+    /// Parentheses reset it, so `if ((x = 1))` stays allowed in strict mode.
+    assignment_end: Option<usize>,
+    /// Paths of the files being imported, innermost last, to catch import cycles.
+    imports: Vec<PathBuf>,
+    /// The chunk's index for the source being compiled, for recording spans.
+    source_id: usize,
 }
 
 struct ParserPointer {
@@ -115,10 +164,197 @@ struct ParserPointer {
 struct ParserState {
     panic_mode: bool,
     had_error: bool,
+    errors: Vec<CompileError>,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CompileOptions {
+    /// Reject an assignment used as an `if` or `while` condition, like `if (x = 1)`.
+    /// Off by default, as assignment is a valid expression there.
+    pub strict_conditions: bool,
+    /// Reject reading or assigning a global that nothing defines: not the script itself,
+    /// an earlier script in the same VM or a native. Off by default, as a function may use
+    /// a global that a later script defines before calling it.
+    pub strict_globals: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompileError {
+    pub message: String,
+    pub line: u32,
+    /// What the error was found at, like ` at 'else'` or ` at end`. Empty when only the line is known.
+    pub location: String,
+    /// Byte offset of the offending token in the source it came from, for editors to underline.
+    pub start: usize,
+    /// Byte length of the offending token. 0 when its position isn't known.
+    pub length: usize,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[line {}] Error{}: {}", self.line, self.location, self.message)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+struct Local {
+    name: String,
+    depth: Option<u32>,
+    is_captured: bool,
+}
+
+/// A loop being compiled, for the `break` and `continue` statements inside it.
+struct Loop {
+    label: Option<String>,
+    /// Where `continue` goes. `None` until it's known, like the condition of a do-while,
+    /// with `continue_jumps` collecting the forward jumps to patch once it is.
+    continue_start: Option<usize>,
+    continue_jumps: Vec<usize>,
+    break_jumps: Vec<usize>,
+    /// Locals deeper than this belong to the loop body and are popped when leaving it early.
+    scope_depth: u32,
+    /// Try blocks already open when the loop started. Leaving the loop drops the ones opened inside it.
+    try_depth: usize,
+}
+
+/// A point in the code where the number of values above the locals is known.
+/// Statements start and end with none, and expressions count theirs from here.
+struct StackMark {
+    offset: usize,
+    temporaries: usize,
+}
+
+struct UpvalueRef {
+    index: u8,
+    is_local: bool,
+}
+
+struct FunctionCompiler {
+    enclosing: Option<Box<FunctionCompiler>>,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: u32,
+    loops: Vec<Loop>,
+    try_depth: usize,
+    stack_mark: StackMark,
+    /// The label before the loop about to be compiled, like `outer` in `outer: while (...)`.
+    /// The loop takes it before compiling its header, so loops in there can't.
+    label: Option<String>,
+}
+
+impl FunctionCompiler {
+    fn new(function_type: FunctionType) -> FunctionCompiler {
+        FunctionCompiler {
+            enclosing: None,
+            function_type,
+            // Slot 0 holds the function being called. Methods name it `this` to reach the receiver.
+            locals: vec![Local {
+                name: match function_type {
+                    FunctionType::Initializer | FunctionType::Method => String::from("this"),
+                    _ => String::new(),
+                },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            try_depth: 0,
+            stack_mark: StackMark { offset: 0, temporaries: 0 },
+            label: None,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Result<Option<u8>, String> {
+        for (i, local) in self.locals.iter().enumerate().rev() {
+            if local.name == name {
+                if local.depth.is_none() {
+                    return Err(String::from("Can't read local variable in its own initializer."));
+                }
+
+                return Ok(Some(i as u8));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Looks the name up in the enclosing functions, threading an upvalue through
+    /// every function in between so each one can hand it down to the next.
+    fn resolve_upvalue(&mut self, name: &str) -> Result<Option<u8>, String> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+
+        if let Some(local) = enclosing.resolve_local(name)? {
+            enclosing.locals[local as usize].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue, false).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, String> {
+        for (i, upvalue) in self.upvalues.iter().enumerate() {
+            if upvalue.index == index && upvalue.is_local == is_local {
+                return Ok(i as u8);
+            }
+        }
+
+        if self.upvalues.len() == MAX_LOCALS {
+            return Err(String::from("Too many closure variables in function."));
+        }
+
+        self.upvalues.push(UpvalueRef { index, is_local });
+
+        Ok((self.upvalues.len() - 1) as u8)
+    }
+}
+
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+pub fn compile(source: &str) -> Result<Chunk, Vec<CompileError>> {
+    compile_with(source, CompileOptions::default())
 }
 
-pub fn compile(source: &String) -> Result<Chunk, ()> {
+pub fn compile_with(source: &str, options: CompileOptions) -> Result<Chunk, Vec<CompileError>> {
     let mut chunk = Chunk::new();
+    compile_into(source, &mut chunk, options)?;
+
+    Ok(chunk)
+}
+
+/// Appends the code for `source` to `chunk` and returns the offset where its script starts.
+/// Earlier code, constants and string literals stay valid, so a REPL can keep one chunk.
+pub fn compile_into(source: &str, chunk: &mut Chunk, options: CompileOptions) -> Result<usize, Vec<CompileError>> {
+    compile_source(source, Vec::new(), chunk, options)
+}
+
+/// Like `compile_into`, for the contents of the file at `path`. The file counts as being
+/// imported already, so importing itself is caught as a cycle right away.
+pub fn compile_file_into(source: &str, path: &str, chunk: &mut Chunk, options: CompileOptions) -> Result<usize, Vec<CompileError>> {
+    let resolved = canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    compile_source(source, vec![resolved], chunk, options)
+}
+
+fn compile_source(source: &str, imports: Vec<PathBuf>, chunk: &mut Chunk, options: CompileOptions) -> Result<usize, Vec<CompileError>> {
+    let entry = chunk.code_size();
+    let source_id = chunk.add_source(source);
     let mut ctx = CompilerContext {
         sp: ScannerPointer::new(),
         pp: ParserPointer {
@@ -128,9 +364,16 @@ pub fn compile(source: &String) -> Result<Chunk, ()> {
         ps: ParserState {
             panic_mode: false,
             had_error: false,
+            errors: Vec::new(),
         },
+        fc: FunctionCompiler::new(FunctionType::Script),
+        classes: Vec::new(),
         can_assign: false,
         line: 1,
+        options,
+        assignment_end: None,
+        imports,
+        source_id,
     };
     advance(source, &mut ctx);
 
@@ -139,17 +382,22 @@ pub fn compile(source: &String) -> Result<Chunk, ()> {
             break;
         }
 
-        declaration(&mut chunk, source, &mut ctx);
+        declaration(chunk, source, &mut ctx);
     }
 
     // expression(&mut chunk, source, &mut ctx);
     consume(TokenType::EOF, "Expect end of expression.", source, &mut ctx);
-    chunk.write(OpCode::Return, ctx.line);
+    chunk.write(OpCode::Nil, ctx.pp.current.line);
+    chunk.write(OpCode::Return, ctx.pp.current.line);
 
-    Ok(chunk)
+    if ctx.ps.had_error {
+        return Err(ctx.ps.errors);
+    }
+
+    Ok(entry)
 }
 
-fn match_token(token_type: TokenType, source: &String, ctx: &mut CompilerContext) -> bool {
+fn match_token(token_type: TokenType, source: &str, ctx: &mut CompilerContext) -> bool {
     if !check(token_type, &ctx.pp) {
         return false;
     }
@@ -163,8 +411,18 @@ fn check(token_type: TokenType, pp: &ParserPointer) -> bool {
     pp.current.token_type == token_type
 }
 
-fn declaration(chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext) {
+/// The type of the token after the current one, scanned without consuming anything.
+fn peek_next(source: &str, ctx: &CompilerContext) -> TokenType {
+    let mut sp = ctx.sp.clone();
+    let mut line = ctx.line;
+    scan_token(source, &mut sp, &mut line).token_type
+}
+
+fn declaration(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    mark_stack(0, chunk, ctx);
     match ctx.pp.current.token_type {
+        TokenType::Class => class_declaration(chunk, source, ctx),
+        TokenType::Fun => fun_declaration(chunk, source, ctx),
         TokenType::Var => var_declaration(chunk, source, ctx),
         _ => statement(chunk, source, ctx),
     }
@@ -172,17 +430,141 @@ fn declaration(chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext) {
     if ctx.ps.panic_mode {
         synchronize(source, ctx);
     }
+    mark_stack(0, chunk, ctx);
 }
 
-fn var_declaration(chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext) {
+fn class_declaration(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
     advance(source, ctx);
 
+    let global = parse_variable("Expect class name.", chunk, source, ctx);
+
+    let global = match global {
+        Ok(global) => global,
+        Err(msg) => {
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            return;
+        },
+    };
+
+    let class_name = ctx.pp.previous.clone();
+    let name = match chunk.add_or_retrieve_string_literal(class_name.lexeme(source)) {
+        Ok(name) => name,
+        Err(msg) => {
+            error_at(&class_name, &msg, &mut ctx.ps);
+            return;
+        },
+    };
+
+    chunk.write(OpCode::Class, class_name.line);
+    emit_string_id(&name, class_name.line, chunk, ctx);
+    define_variable(&global, chunk, ctx);
+
+    ctx.classes.push(ClassCompiler { has_superclass: false });
+
+    let class_lexeme = class_name.lexeme(source);
+    if match_token(TokenType::Less, source, ctx) {
+        consume(TokenType::Identifier, "Expect superclass name.", source, ctx);
+
+        let superclass = ctx.pp.previous.lexeme(source);
+        named_variable(superclass, ctx.pp.previous.line, false, chunk, source, ctx);
+        if superclass == class_lexeme {
+            error_at(&ctx.pp.previous, "A class can't inherit from itself.", &mut ctx.ps);
+        }
+
+        // Each subclass gets its own scope holding `super`, so methods can capture it.
+        begin_scope(ctx);
+        add_local("super", ctx);
+        define_variable(&None, chunk, ctx);
+
+        named_variable(class_lexeme, class_name.line, false, chunk, source, ctx);
+        chunk.write(OpCode::Inherit, ctx.pp.previous.line);
+        if let Some(class) = ctx.classes.last_mut() {
+            class.has_superclass = true;
+        }
+    }
+
+    // Load the class back onto the stack so methods can be attached to it.
+    named_variable(class_lexeme, class_name.line, false, chunk, source, ctx);
+    consume(TokenType::LeftBrace, "Expect '{' before class body.", source, ctx);
+    while !check(TokenType::RightBrace, &ctx.pp) && !check(TokenType::EOF, &ctx.pp) {
+        method(chunk, source, ctx);
+    }
+    consume(TokenType::RightBrace, "Expect '}' after class body.", source, ctx);
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+
+    if let Some(class) = ctx.classes.pop() {
+        if class.has_superclass {
+            end_scope(chunk, ctx);
+        }
+    }
+}
+
+fn method(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    consume(TokenType::Identifier, "Expect method name.", source, ctx);
+
+    let lexeme = ctx.pp.previous.lexeme(source);
+    let name = match chunk.add_or_retrieve_string_literal(lexeme) {
+        Ok(name) => name,
+        Err(msg) => {
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            return;
+        },
+    };
+
+    let function_type = if lexeme == "init" {
+        FunctionType::Initializer
+    } else {
+        FunctionType::Method
+    };
+
+    function(function_type, Some(name.clone()), chunk, source, ctx);
+    chunk.write(OpCode::Method, ctx.pp.previous.line);
+    emit_string_id(&name, ctx.pp.previous.line, chunk, ctx);
+}
+
+fn fun_declaration(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    advance(source, ctx);
+
+    let global = parse_variable("Expect function name.", chunk, source, ctx);
+
+    let global = match global {
+        Ok(global) => global,
+        Err(msg) => {
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            return;
+        },
+    };
+
+    // Mark the name initialized right away so the body can refer to itself recursively.
+    mark_initialized(ctx);
+
+    let name = ctx.pp.previous.lexeme(source);
+    let name = match chunk.add_or_retrieve_string_literal(name) {
+        Ok(name) => name,
+        Err(msg) => {
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            return;
+        },
+    };
+
+    function(FunctionType::Function, Some(name), chunk, source, ctx);
+    define_variable(&global, chunk, ctx);
+}
+
+fn var_declaration(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    advance(source, ctx);
+
+    if match_token(TokenType::LeftParen, source, ctx) {
+        destructuring_declaration(chunk, source, ctx);
+        return;
+    }
+
     let global = parse_variable("Expect variable name.", chunk, source, ctx);
 
     let global = match global {
         Ok(global) => global,
         Err(msg) => {
-            error_at(ctx.pp.previous.line, &msg, &mut ctx.ps);
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
             return;
         },
     };
@@ -198,39 +580,148 @@ fn var_declaration(chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext
     define_variable(&global, chunk, ctx);
 }
 
-fn parse_variable(error_msg: &str, chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext) -> Result<StringId, String>{
+/// `var (a, b) = list;` defines one variable per item of a list of exactly that many items,
+/// which is how functions return several values.
+fn destructuring_declaration(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let mut names = Vec::new();
+    loop {
+        consume(TokenType::Identifier, "Expect variable name.", source, ctx);
+        names.push(ctx.pp.previous.clone());
+        if !match_token(TokenType::Comma, source, ctx) {
+            break;
+        }
+    }
+    consume(TokenType::RightParen, "Expect ')' after variable names.", source, ctx);
+    if names.len() > u8::MAX as usize {
+        error_at(&ctx.pp.previous, "Can't unpack more than 255 values.", &mut ctx.ps);
+        return;
+    }
+
+    consume(TokenType::Equal, "Expect '=' after variable names.", source, ctx);
+    // Globals aren't defined until the value has been unpacked, so the hidden locals below
+    // need a scope of their own.
+    let global = ctx.fc.scope_depth == 0;
+    if global {
+        begin_scope(ctx);
+    }
+    expression(chunk, source, ctx);
+    consume(TokenType::Semicolon, "Expect ';' after variable declaration.", source, ctx);
+
+    let line = ctx.pp.previous.line;
+    chunk.write(OpCode::Unpack, line);
+    chunk.write_u8(names.len() as u8, line);
+
+    // The unpacked items sit on the stack in order, which is where the new locals live.
+    // `declare_variable` takes the name from the previous token, so each name stands in for it.
+    let previous = ctx.pp.previous.clone();
+    for name in names.iter() {
+        if global {
+            add_local(" unpacked", ctx);
+        } else {
+            ctx.pp.previous = name.clone();
+            declare_variable(source, ctx);
+        }
+        mark_initialized(ctx);
+    }
+    ctx.pp.previous = previous;
+
+    if global {
+        let first = ctx.fc.locals.len() - names.len();
+        for (i, name) in names.iter().enumerate() {
+            match identifier_constant(name.lexeme(source), name.line, chunk) {
+                Ok(id) => {
+                    chunk.write(OpCode::GetLocal, name.line);
+                    chunk.write_u8((first + i) as u8, name.line);
+                    emit_global(OpCode::DefineGlobal, &id, name.line, chunk, ctx);
+                },
+                Err(msg) => error_at(name, &msg, &mut ctx.ps),
+            }
+        }
+        end_scope(chunk, ctx);
+    }
+}
+
+/// Parses a variable name. Globals are pushed as string literals and returned as `Some(id)`.
+/// Locals live on the stack, so they only get declared and `None` is returned.
+fn parse_variable(error_msg: &str, chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) -> Result<Option<StringId>, String> {
     consume(TokenType::Identifier, error_msg, source, ctx);
-    identifier_constant(chunk, source, ctx)
+
+    if ctx.fc.scope_depth > 0 {
+        declare_variable(source, ctx);
+        return Ok(None);
+    }
+
+    let name = ctx.pp.previous.lexeme(source);
+    identifier_constant(name, ctx.pp.previous.line, chunk).map(Some)
 }
 
-fn identifier_constant(chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext) -> Result<StringId, String> {
-    let name = &source[ctx.pp.previous.start..ctx.pp.previous.start + ctx.pp.previous.length];
-    
-    chunk.write(OpCode::StringLiteral, ctx.pp.previous.line);
-    let idx = chunk.add_or_retrieve_string_literal(name);
+fn identifier_constant(name: &str, line: u32, chunk: &mut Chunk) -> Result<StringId, String> {
+    chunk.emit_string(name, line).map_err(|msg| format!("Failed to add string literal: {}", msg))
+}
 
-    match idx {
-        Ok(idx) => {
-            chunk
-                .write_string_literal_id(&idx, ctx.pp.previous.line)
-                .expect("Failed to write variable as string literal id");
-            
-            return Ok(idx);
-        },
-        Err(msg) => {
-            let msg = format!("Failed to add string literal: {}", msg);
+fn declare_variable(source: &str, ctx: &mut CompilerContext) {
+    let name = ctx.pp.previous.lexeme(source);
+
+    for local in ctx.fc.locals.iter().rev() {
+        if let Some(depth) = local.depth {
+            if depth < ctx.fc.scope_depth {
+                break;
+            }
+        }
 
-            return Err(msg);
+        if local.name == name {
+            error_at(&ctx.pp.previous, "Already a variable with this name in this scope.", &mut ctx.ps);
+            return;
+        }
+    }
+
+    add_local(name, ctx);
+}
+
+fn add_local(name: &str, ctx: &mut CompilerContext) {
+    if ctx.fc.locals.len() == MAX_LOCALS {
+        error_at(&ctx.pp.previous, "Too many local variables in function.", &mut ctx.ps);
+        return;
+    }
+
+    ctx.fc.locals.push(Local { name: String::from(name), depth: None, is_captured: false });
+}
+
+fn mark_initialized(ctx: &mut CompilerContext) {
+    if ctx.fc.scope_depth == 0 {
+        return;
+    }
+
+    let depth = ctx.fc.scope_depth;
+    if let Some(local) = ctx.fc.locals.last_mut() {
+        local.depth = Some(depth);
+    }
+}
+
+fn define_variable(global: &Option<StringId>, chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    match global {
+        Some(global) => {
+            emit_global(OpCode::DefineGlobal, global, ctx.pp.previous.line, chunk, ctx);
         },
+        None => mark_initialized(ctx),
+    }
+}
+
+/// Writes a global instruction for `id`, in its long form when the id needs it.
+fn emit_global(opcode: OpCode, id: &StringId, line: u32, chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    if let Err(msg) = chunk.write_global(opcode, id, line) {
+        error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
     }
 }
 
-fn define_variable(global: &StringId, chunk: &mut Chunk, ctx: &mut CompilerContext) {
-    chunk.write(OpCode::DefineGlobal, ctx.pp.previous.line);
-    chunk.write_u8(global.0 as u8, ctx.pp.previous.line);
+/// Writes a string literal id as a one-byte operand, reporting ids that don't fit.
+fn emit_string_id(id: &StringId, line: u32, chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    if let Err(msg) = chunk.write_string_literal_id(id, line) {
+        error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+    }
 }
 
-fn synchronize(source: &String, ctx: &mut CompilerContext) {
+fn synchronize(source: &str, ctx: &mut CompilerContext) {
     ctx.ps.panic_mode = false;
 
     while ctx.pp.current.token_type != TokenType::EOF {
@@ -240,13 +731,19 @@ fn synchronize(source: &String, ctx: &mut CompilerContext) {
 
         match ctx.pp.current.token_type {
             TokenType::Class | 
+            TokenType::Del | 
+            TokenType::Do | 
             TokenType::Fun | 
+            TokenType::Import | 
             TokenType::Var | 
             TokenType::For | 
             TokenType::If | 
             TokenType::While | 
             TokenType::Print | 
-            TokenType::Return => return,
+            TokenType::EPrint | 
+            TokenType::Return | 
+            TokenType::Throw | 
+            TokenType::Try => return,
             _ => (),
         }
 
@@ -254,136 +751,1203 @@ fn synchronize(source: &String, ctx: &mut CompilerContext) {
     }
 }
 
-fn statement(chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext) {
-    if match_token(TokenType::Print, source, ctx) {
+fn statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    mark_stack(0, chunk, ctx);
+    if check(TokenType::Identifier, &ctx.pp) && peek_next(source, ctx) == TokenType::Colon {
+        labeled_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Print, source, ctx) {
         print_statement(chunk, source, ctx);
+    } else if match_token(TokenType::EPrint, source, ctx) {
+        eprint_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Import, source, ctx) {
+        import_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Return, source, ctx) {
+        return_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Throw, source, ctx) {
+        throw_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Try, source, ctx) {
+        try_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Del, source, ctx) {
+        del_statement(chunk, source, ctx);
+    } else if match_token(TokenType::If, source, ctx) {
+        if_statement(chunk, source, ctx);
+    } else if match_token(TokenType::While, source, ctx) {
+        while_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Do, source, ctx) {
+        do_while_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Break, source, ctx) {
+        break_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Continue, source, ctx) {
+        continue_statement(chunk, source, ctx);
+    } else if match_token(TokenType::For, source, ctx) {
+        for_statement(chunk, source, ctx);
+    } else if match_token(TokenType::LeftBrace, source, ctx) {
+        begin_scope(ctx);
+        block(chunk, source, ctx);
+        end_scope(chunk, ctx);
     } else {
         expression_statement(chunk, source, ctx);
     }
+    mark_stack(0, chunk, ctx);
 }
 
-fn print_statement(chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext) {
-    expression(chunk, source, ctx);
-    consume(TokenType::Semicolon, "Expect ';' after value.", source, ctx);
-    chunk.write(OpCode::Print, ctx.pp.previous.line);
+/// Records that the code up to here leaves `temporaries` values above the locals.
+fn mark_stack(temporaries: usize, chunk: &Chunk, ctx: &mut CompilerContext) {
+    ctx.fc.stack_mark = StackMark { offset: chunk.code_size(), temporaries };
 }
 
-fn expression_statement(chunk: &mut Chunk, source: &String, ctx: &mut CompilerContext) {
-    expression(chunk, source, ctx);
-    consume(TokenType::Semicolon, "Expect ';' after expression.", source, ctx);
-    chunk.write(OpCode::Pop, ctx.pp.previous.line);
-}
+/// How many values the expression being compiled has pushed above the locals so far.
+fn temporaries(chunk: &Chunk, ctx: &CompilerContext) -> usize {
+    let mark = &ctx.fc.stack_mark;
+    let effect = chunk.stack_effect(mark.offset, chunk.code_size());
 
-fn expression(
-    chunk: &mut Chunk, 
-    source: &String, 
-    ctx: &mut CompilerContext
-) {
-    parse_precedence(Precedence::Assignment, chunk, source, ctx);
+    (mark.temporaries as isize + effect).max(0) as usize
 }
 
-fn variable(
-    chunk: &mut Chunk, 
-    source: &String, 
-    ctx: &mut CompilerContext
-) {
-    named_variable(chunk, source, ctx);
-}
+/// Compiles `import "path";` by compiling the file's declarations in place, as if its text were here.
+/// Paths are relative to the working directory.
+fn import_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    if ctx.fc.function_type != FunctionType::Script || ctx.fc.scope_depth > 0 {
+        error_at_token(&ctx.pp.previous, "Can only import at the top level.", source, &mut ctx.ps);
+        return;
+    }
 
-fn named_variable(
-    chunk: &mut Chunk,
-    source: &String,
-    ctx: &mut CompilerContext
-) {
-    let arg = identifier_constant(chunk, source, ctx);
+    consume(TokenType::String, "Expect file path after 'import'.", source, ctx);
+    if ctx.ps.panic_mode {
+        return;
+    }
+    let path_token = ctx.pp.previous.clone();
+    let lexeme = path_token.lexeme(source);
+    let path = lexeme[1..lexeme.len() - 1].to_string();
+    consume(TokenType::Semicolon, "Expect ';' after import path.", source, ctx);
+    if ctx.ps.panic_mode {
+        return;
+    }
 
-    match arg {
-        Ok(arg) => {
-            let arg = arg.0 as u8;
-            if ctx.can_assign && match_token(TokenType::Equal, source, ctx) {
-                expression(chunk, source, ctx);
-                chunk.write(OpCode::SetGlobal, ctx.pp.previous.line);
-                chunk.write_u8(arg, ctx.pp.previous.line);
-            } else {
-                chunk.write(OpCode::GetGlobal, ctx.pp.previous.line);
-                chunk.write_u8(arg, ctx.pp.previous.line);
-            }
+    let imported = match read_to_string(&path) {
+        Ok(imported) => imported,
+        Err(err) => {
+            let msg = format!("Can't import '{}': {}.", path, err);
+            error_at_token(&path_token, &msg, source, &mut ctx.ps);
+            return;
         },
-        Err(msg) => error_at(ctx.pp.previous.line, &msg, &mut ctx.ps),
-    }
-}
+    };
 
-fn string(
-    chunk: &mut Chunk, 
-    source: &String, 
-    ctx: &mut CompilerContext
-) {
-    let string = &source[(ctx.pp.previous.start + 1)..(ctx.pp.previous.start + ctx.pp.previous.length - 1)];
-    
-    chunk.write(OpCode::StringLiteral, ctx.pp.previous.line);
-    let idx = chunk.add_or_retrieve_string_literal(string);
+    // A file that doesn't resolve can't be on the stack already, as it was just read.
+    let resolved = canonicalize(&path).unwrap_or_else(|_| PathBuf::from(&path));
+    if ctx.imports.contains(&resolved) {
+        let msg = format!("Import cycle: '{}' is already being imported.", path);
+        error_at_token(&path_token, &msg, source, &mut ctx.ps);
+        return;
+    }
 
-    match idx {
-        Ok(idx) => 
-            chunk
-                .write_string_literal_id(&idx, ctx.pp.previous.line)
-                .expect("Failed to write string literal id"),
-        Err(msg) => error_at(ctx.pp.previous.line, &msg, &mut ctx.ps),
+    // Scan the imported file from its start, then pick up again after the `;`.
+    let sp = std::mem::take(&mut ctx.sp);
+    let line = std::mem::replace(&mut ctx.line, 1);
+    let source_id = std::mem::replace(&mut ctx.source_id, chunk.add_source(&imported));
+    let previous = ctx.pp.previous.clone();
+    let current = ctx.pp.current.clone();
+    ctx.imports.push(resolved);
+
+    advance(&imported, ctx);
+    while !match_token(TokenType::EOF, &imported, ctx) {
+        declaration(chunk, &imported, ctx);
     }
+
+    ctx.imports.pop();
+    ctx.sp = sp;
+    ctx.line = line;
+    ctx.source_id = source_id;
+    ctx.pp.previous = previous;
+    ctx.pp.current = current;
+    ctx.ps.panic_mode = false;
 }
 
-fn number(
-    chunk: &mut Chunk, 
-    source: &String, 
-    ctx: &mut CompilerContext
-) {
-    let number = &source[ctx.pp.previous.start..ctx.pp.previous.start + ctx.pp.previous.length];
-    let number = number.parse::<f64>().unwrap();
-    
-    chunk.write(OpCode::Constant, ctx.pp.previous.line);
-    let idx = chunk.add_constant(Value::Number(number));
-    match idx {
-        Ok(idx) => chunk.write_u8(idx, ctx.pp.previous.line),
-        Err(msg) => error_at(ctx.pp.previous.line, &msg, &mut ctx.ps),
+fn block(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    while !check(TokenType::RightBrace, &ctx.pp) && !check(TokenType::EOF, &ctx.pp) {
+        declaration(chunk, source, ctx);
     }
-}
 
-fn grouping(
-    chunk: &mut Chunk,
-    source: &String, 
-    ctx: &mut CompilerContext
-) {
-    expression(chunk, source, ctx);
-    consume(TokenType::RightParen, "Expect ')' after expression.", source, ctx);
+    consume(TokenType::RightBrace, "Expect '}' after block.", source, ctx);
 }
 
-fn unary(
-    chunk: &mut Chunk,
-    source: &String, 
-    ctx: &mut CompilerContext
-) {
-    let operator_type = ctx.pp.previous.token_type;
-
-    parse_precedence(Precedence::Unary, chunk, source, ctx);
+fn begin_scope(ctx: &mut CompilerContext) {
+    ctx.fc.scope_depth += 1;
+}
 
-    match operator_type {
-        TokenType::Bang => chunk.write(OpCode::Not, ctx.pp.previous.line),
-        TokenType::Minus => chunk.write(OpCode::Negate, ctx.pp.previous.line),
-        _ => panic!("Unknown unary operator: {:?}", operator_type),
+fn end_scope(chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    ctx.fc.scope_depth -= 1;
+
+    while let Some(local) = ctx.fc.locals.last() {
+        match local.depth {
+            Some(depth) if depth <= ctx.fc.scope_depth => break,
+            _ => {
+                if local.is_captured {
+                    chunk.write(OpCode::CloseUpvalue, ctx.pp.previous.line);
+                } else {
+                    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+                }
+                ctx.fc.locals.pop();
+            },
+        }
     }
 }
 
-fn binary(
-    chunk: &mut Chunk,
-    source: &String,
+fn return_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    if ctx.fc.function_type == FunctionType::Script {
+        error_at(&ctx.pp.previous, "Can't return from top-level code.", &mut ctx.ps);
+    }
+
+    if match_token(TokenType::Semicolon, source, ctx) {
+        emit_return(chunk, ctx);
+    } else {
+        if ctx.fc.function_type == FunctionType::Initializer {
+            error_at(&ctx.pp.previous, "Can't return a value from an initializer.", &mut ctx.ps);
+        }
+
+        expression(chunk, source, ctx);
+        consume(TokenType::Semicolon, "Expect ';' after return value.", source, ctx);
+        chunk.write(OpCode::Return, ctx.pp.previous.line);
+    }
+}
+
+/// `throw value;` stops the script with a runtime error whose message is the value as `print` shows it.
+fn throw_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let keyword = ctx.pp.previous.clone();
+    expression(chunk, source, ctx);
+    consume(TokenType::Semicolon, "Expect ';' after thrown value.", source, ctx);
+    chunk.write(OpCode::Throw, keyword.line);
+    record_span(chunk.code_size() - 1, &keyword, chunk, ctx);
+}
+
+/// `try { ... } catch (e) { ... }` runs the catch block with the error message in `e` when a
+/// runtime error or `throw` happens in the try block, including in functions it calls.
+fn try_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let handler = emit_jump(OpCode::PushHandler, chunk, ctx);
+
+    consume(TokenType::LeftBrace, "Expect '{' after 'try'.", source, ctx);
+    ctx.fc.try_depth += 1;
+    begin_scope(ctx);
+    block(chunk, source, ctx);
+    end_scope(chunk, ctx);
+    ctx.fc.try_depth -= 1;
+    chunk.write(OpCode::PopHandler, ctx.pp.previous.line);
+    let end_jump = emit_jump(OpCode::Jump, chunk, ctx);
+
+    // The VM unwinds to where the try started and pushes the message, which becomes `e`.
+    patch_jump(handler, chunk, ctx);
+    consume(TokenType::Catch, "Expect 'catch' after try block.", source, ctx);
+    consume(TokenType::LeftParen, "Expect '(' after 'catch'.", source, ctx);
+    consume(TokenType::Identifier, "Expect error variable name.", source, ctx);
+    begin_scope(ctx);
+    let name = ctx.pp.previous.clone();
+    add_local(name.lexeme(source), ctx);
+    mark_initialized(ctx);
+    consume(TokenType::RightParen, "Expect ')' after error variable.", source, ctx);
+    consume(TokenType::LeftBrace, "Expect '{' before catch block.", source, ctx);
+    block(chunk, source, ctx);
+    end_scope(chunk, ctx);
+
+    patch_jump(end_jump, chunk, ctx);
+}
+
+/// `del name;` removes a global binding. Locals and upvalues can't be deleted.
+fn del_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    consume(TokenType::Identifier, "Expect variable name after 'del'.", source, ctx);
+    let name = ctx.pp.previous.lexeme(source);
+    let line = ctx.pp.previous.line;
+
+    if ctx.fc.scope_depth > 0 || ctx.fc.enclosing.is_some() {
+        let is_local = matches!(ctx.fc.resolve_local(name), Ok(Some(_)) | Err(_));
+        if is_local || matches!(ctx.fc.resolve_upvalue(name), Ok(Some(_))) {
+            error_at(&ctx.pp.previous, "Can only delete global variables.", &mut ctx.ps);
+            return;
+        }
+    }
+
+    match identifier_constant(name, line, chunk) {
+        Ok(global) => emit_global(OpCode::DeleteGlobal, &global, line, chunk, ctx),
+        Err(msg) => error_at(&ctx.pp.previous, &msg, &mut ctx.ps),
+    }
+
+    consume(TokenType::Semicolon, "Expect ';' after variable name.", source, ctx);
+}
+
+/// Compiles an `if` and any `else if` ladder after it as one flat chain.
+/// Each condition is popped by the jump that tests it, and every branch but the last
+/// jumps straight to the end instead of through nested ifs.
+fn if_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let mut exit_jumps = Vec::new();
+
+    loop {
+        consume(TokenType::LeftParen, "Expect '(' after 'if'.", source, ctx);
+        condition(chunk, source, ctx);
+        consume(TokenType::RightParen, "Expect ')' after condition.", source, ctx);
+
+        let then_jump = emit_jump(OpCode::PopJumpIfFalse, chunk, ctx);
+        statement(chunk, source, ctx);
+
+        // Without an `else`, the false branch already lands at the end.
+        if !check(TokenType::Else, &ctx.pp) {
+            patch_jump(then_jump, chunk, ctx);
+            break;
+        }
+
+        advance(source, ctx);
+        exit_jumps.push(emit_jump(OpCode::Jump, chunk, ctx));
+        patch_jump(then_jump, chunk, ctx);
+
+        if !match_token(TokenType::If, source, ctx) {
+            statement(chunk, source, ctx);
+            break;
+        }
+    }
+
+    for exit_jump in exit_jumps {
+        patch_jump(exit_jump, chunk, ctx);
+    }
+}
+
+fn while_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let label = ctx.fc.label.take();
+    let loop_start = chunk.code_size();
+    consume(TokenType::LeftParen, "Expect '(' after 'while'.", source, ctx);
+    condition(chunk, source, ctx);
+    consume(TokenType::RightParen, "Expect ')' after condition.", source, ctx);
+
+    let exit_jump = emit_jump(OpCode::JumpIfFalse, chunk, ctx);
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+    begin_loop(label, Some(loop_start), ctx);
+    statement(chunk, source, ctx);
+    emit_loop(loop_start, chunk, ctx);
+
+    patch_jump(exit_jump, chunk, ctx);
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+    end_loop(chunk, ctx);
+}
+
+/// `do body while (condition);` runs the body once before checking the condition.
+fn do_while_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let loop_start = chunk.code_size();
+    begin_loop(ctx.fc.label.take(), None, ctx);
+    statement(chunk, source, ctx);
+
+    consume(TokenType::While, "Expect 'while' after do body.", source, ctx);
+    consume(TokenType::LeftParen, "Expect '(' after 'while'.", source, ctx);
+    set_continue_start(chunk, ctx);
+    condition(chunk, source, ctx);
+    consume(TokenType::RightParen, "Expect ')' after condition.", source, ctx);
+    consume(TokenType::Semicolon, "Expect ';' after do-while condition.", source, ctx);
+
+    let exit_jump = emit_jump(OpCode::JumpIfFalse, chunk, ctx);
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+    emit_loop(loop_start, chunk, ctx);
+
+    patch_jump(exit_jump, chunk, ctx);
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+    end_loop(chunk, ctx);
+}
+
+/// `label: loop` names the loop so `break label;` and `continue label;` in nested loops can reach it.
+fn labeled_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    advance(source, ctx);
+    let label = ctx.pp.previous.lexeme(source).to_string();
+    advance(source, ctx);
+
+    ctx.fc.label = Some(label);
+    if match_token(TokenType::While, source, ctx) {
+        while_statement(chunk, source, ctx);
+    } else if match_token(TokenType::Do, source, ctx) {
+        do_while_statement(chunk, source, ctx);
+    } else if match_token(TokenType::For, source, ctx) {
+        for_statement(chunk, source, ctx);
+    } else {
+        ctx.fc.label = None;
+        error_at_token(&ctx.pp.current, "Expect a loop after label.", source, &mut ctx.ps);
+    }
+}
+
+/// Starts the loop whose body comes next. The body's locals are the ones declared from here on.
+fn begin_loop(label: Option<String>, continue_start: Option<usize>, ctx: &mut CompilerContext) {
+    let loop_ = Loop {
+        label,
+        continue_start,
+        continue_jumps: Vec::new(),
+        break_jumps: Vec::new(),
+        scope_depth: ctx.fc.scope_depth,
+        try_depth: ctx.fc.try_depth,
+    };
+    ctx.fc.loops.push(loop_);
+}
+
+/// Marks the current position as where `continue` goes in the innermost loop.
+fn set_continue_start(chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    let jumps = match ctx.fc.loops.last_mut() {
+        Some(loop_) => {
+            loop_.continue_start = Some(chunk.code_size());
+            std::mem::take(&mut loop_.continue_jumps)
+        },
+        None => return,
+    };
+
+    for jump in jumps {
+        patch_jump(jump, chunk, ctx);
+    }
+}
+
+/// Ends the innermost loop. Its `break`s land here.
+fn end_loop(chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    if let Some(loop_) = ctx.fc.loops.pop() {
+        for jump in loop_.break_jumps {
+            patch_jump(jump, chunk, ctx);
+        }
+    }
+}
+
+fn break_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let index = match loop_target("break", source, ctx) {
+        Some(index) => index,
+        None => return,
+    };
+
+    leave_loop_body(index, chunk, ctx);
+    let jump = emit_jump(OpCode::Jump, chunk, ctx);
+    ctx.fc.loops[index].break_jumps.push(jump);
+}
+
+fn continue_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let index = match loop_target("continue", source, ctx) {
+        Some(index) => index,
+        None => return,
+    };
+
+    leave_loop_body(index, chunk, ctx);
+    match ctx.fc.loops[index].continue_start {
+        Some(start) => emit_loop(start, chunk, ctx),
+        None => {
+            let jump = emit_jump(OpCode::Jump, chunk, ctx);
+            ctx.fc.loops[index].continue_jumps.push(jump);
+        },
+    }
+}
+
+/// Parses the optional label and `;` after `break` or `continue` and finds the loop it targets:
+/// the labeled one, or the innermost without a label.
+fn loop_target(keyword: &str, source: &str, ctx: &mut CompilerContext) -> Option<usize> {
+    let label = if match_token(TokenType::Identifier, source, ctx) {
+        Some(ctx.pp.previous.clone())
+    } else {
+        None
+    };
+    consume(TokenType::Semicolon, &format!("Expect ';' after '{}'.", keyword), source, ctx);
+
+    let index = match &label {
+        Some(label) => {
+            let name = label.lexeme(source);
+            ctx.fc.loops.iter().rposition(|loop_| loop_.label.as_deref() == Some(name))
+        },
+        None => ctx.fc.loops.len().checked_sub(1),
+    };
+
+    if index.is_none() {
+        match &label {
+            Some(label) => {
+                let msg = format!("No enclosing loop labeled '{}'.", label.lexeme(source));
+                error_at_token(label, &msg, source, &mut ctx.ps);
+            },
+            None => {
+                let msg = format!("Can't use '{}' outside of a loop.", keyword);
+                error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            },
+        }
+    }
+
+    index
+}
+
+/// Pops the locals and closes the try blocks that are inside the loop at `index`, without
+/// forgetting them, as the code after a `break` or `continue` still sees them.
+fn leave_loop_body(index: usize, chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    let line = ctx.pp.previous.line;
+    let loop_depth = ctx.fc.loops[index].scope_depth;
+
+    for local in ctx.fc.locals.iter().rev() {
+        match local.depth {
+            Some(depth) if depth <= loop_depth => break,
+            _ if local.is_captured => chunk.write(OpCode::CloseUpvalue, line),
+            _ => chunk.write(OpCode::Pop, line),
+        }
+    }
+
+    for _ in ctx.fc.loops[index].try_depth..ctx.fc.try_depth {
+        chunk.write(OpCode::PopHandler, line);
+    }
+}
+
+fn condition(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    ctx.assignment_end = None;
+    expression(chunk, source, ctx);
+
+    if ctx.options.strict_conditions && ctx.assignment_end == Some(chunk.code_size()) {
+        error_at(&ctx.pp.previous, "Assignment used as a condition; did you mean '=='?", &mut ctx.ps);
+    }
+}
+
+/// Compiles `for (name in iterable) body`. The iterable and a counter live in hidden locals,
+/// and every iteration binds `name` in a scope of its own, so closures capture that iteration's item.
+fn for_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let label = ctx.fc.label.take();
+    begin_scope(ctx);
+    consume(TokenType::LeftParen, "Expect '(' after 'for'.", source, ctx);
+    consume(TokenType::Identifier, "Expect loop variable name.", source, ctx);
+    let name = ctx.pp.previous.clone();
+    consume(TokenType::In, "Expect 'in' after loop variable.", source, ctx);
+    expression(chunk, source, ctx);
+    consume(TokenType::RightParen, "Expect ')' after loop iterable.", source, ctx);
+
+    // The spaces keep these names out of reach of user code.
+    let line = ctx.pp.previous.line;
+    chunk.write(OpCode::GetIter, line);
+    add_local(" iterable", ctx);
+    mark_initialized(ctx);
+    let slot = (ctx.fc.locals.len() - 1) as u8;
+    chunk.write(OpCode::Int, line);
+    chunk.write_u8(0, line);
+    add_local(" index", ctx);
+    mark_initialized(ctx);
+
+    let loop_start = chunk.code_size();
+    begin_loop(label, Some(loop_start), ctx);
+    chunk.write(OpCode::IterNext, line);
+    chunk.write_u8(slot, line);
+    let exit_jump = chunk.code_size();
+    chunk.write_u8(0xff, line);
+    chunk.write_u8(0xff, line);
+
+    begin_scope(ctx);
+    add_local(name.lexeme(source), ctx);
+    mark_initialized(ctx);
+    statement(chunk, source, ctx);
+    end_scope(chunk, ctx);
+    emit_loop(loop_start, chunk, ctx);
+
+    patch_jump(exit_jump, chunk, ctx);
+    end_loop(chunk, ctx);
+    end_scope(chunk, ctx);
+}
+
+/// Records `token` as the source of the instruction at `offset`, so a runtime error there can point at it.
+fn record_span(offset: usize, token: &Token, chunk: &mut Chunk, ctx: &CompilerContext) {
+    chunk.set_span(offset, Span {
+        source: ctx.source_id,
+        start: token.start,
+        length: token.length,
+        line: token.line,
+    });
+}
+
+fn emit_loop(loop_start: usize, chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    chunk.write(OpCode::Loop, ctx.pp.previous.line);
+
+    // +2 to jump back over the loop's own operand as well.
+    let offset = chunk.code_size() - loop_start + 2;
+    if offset > u16::MAX as usize {
+        error_at(&ctx.pp.previous, "Loop body too large.", &mut ctx.ps);
+    }
+
+    chunk.write_u8(((offset >> 8) & 0xff) as u8, ctx.pp.previous.line);
+    chunk.write_u8((offset & 0xff) as u8, ctx.pp.previous.line);
+}
+
+fn emit_return(chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    // Initializers always hand back the instance, which lives in slot 0.
+    if ctx.fc.function_type == FunctionType::Initializer {
+        chunk.write(OpCode::GetLocal, ctx.pp.previous.line);
+        chunk.write_u8(0, ctx.pp.previous.line);
+    } else {
+        chunk.write(OpCode::Nil, ctx.pp.previous.line);
+    }
+    chunk.write(OpCode::Return, ctx.pp.previous.line);
+}
+
+fn emit_jump(opcode: OpCode, chunk: &mut Chunk, ctx: &mut CompilerContext) -> usize {
+    chunk.write(opcode, ctx.pp.previous.line);
+    chunk.write_u8(0xff, ctx.pp.previous.line);
+    chunk.write_u8(0xff, ctx.pp.previous.line);
+
+    chunk.code_size() - 2
+}
+
+fn patch_jump(offset: usize, chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    // -2 to adjust for the bytecode for the jump offset itself.
+    let jump = chunk.code_size() - offset - 2;
+
+    if jump > u16::MAX as usize {
+        error_at(&ctx.pp.previous, "Too much code to jump over.", &mut ctx.ps);
+        return;
+    }
+
+    chunk.patch_u8(offset, ((jump >> 8) & 0xff) as u8);
+    chunk.patch_u8(offset + 1, (jump & 0xff) as u8);
+}
+
+/// Compiles a function body in place. Every function shares the script's chunk,
+/// so the body is jumped over and the function value only records where it starts.
+fn function(function_type: FunctionType, name: Option<StringId>, chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    let (jump, entry) = begin_function(function_type, chunk, ctx);
+
+    consume(TokenType::LeftParen, "Expect '(' after function name.", source, ctx);
+    let mut arity: usize = 0;
+    if !check(TokenType::RightParen, &ctx.pp) {
+        loop {
+            arity += 1;
+            if arity > u8::MAX as usize {
+                error_at(&ctx.pp.current, "Can't have more than 255 parameters.", &mut ctx.ps);
+            }
+
+            match parse_variable("Expect parameter name.", chunk, source, ctx) {
+                Ok(param) => define_variable(&param, chunk, ctx),
+                Err(msg) => error_at(&ctx.pp.previous, &msg, &mut ctx.ps),
+            }
+
+            if !match_token(TokenType::Comma, source, ctx) {
+                break;
+            }
+        }
+    }
+    consume(TokenType::RightParen, "Expect ')' after parameters.", source, ctx);
+    consume(TokenType::LeftBrace, "Expect '{' before function body.", source, ctx);
+    block(chunk, source, ctx);
+    emit_return(chunk, ctx);
+
+    end_function(jump, entry, name, arity, chunk, ctx);
+}
+
+/// Starts compiling a function body in place, behind a jump so the enclosing code skips it.
+/// Returns the jump and the body's entry for `end_function`.
+fn begin_function(function_type: FunctionType, chunk: &mut Chunk, ctx: &mut CompilerContext) -> (usize, usize) {
+    let jump = emit_jump(OpCode::Jump, chunk, ctx);
+    let entry = chunk.code_size();
+    mark_stack(temporaries(chunk, ctx), chunk, ctx);
+
+    let enclosing = std::mem::replace(&mut ctx.fc, FunctionCompiler::new(function_type));
+    ctx.fc.enclosing = Some(Box::new(enclosing));
+    begin_scope(ctx);
+
+    (jump, entry)
+}
+
+/// Finishes a body started by `begin_function` and emits the closure for it.
+fn end_function(jump: usize, entry: usize, name: Option<StringId>, arity: usize, chunk: &mut Chunk, ctx: &mut CompilerContext) {
+    let enclosing = ctx.fc.enclosing.take().expect("Function compiler without enclosing compiler");
+    let compiled = std::mem::replace(&mut ctx.fc, *enclosing);
+    patch_jump(jump, chunk, ctx);
+    // The body leaves the enclosing function's stack as it was.
+    ctx.fc.stack_mark.offset = chunk.code_size();
+
+    let function = Function {
+        name,
+        arity: arity as u8,
+        entry,
+        upvalue_count: compiled.upvalues.len(),
+    };
+
+    // `Closure` only has room for a one-byte constant index.
+    let idx = chunk.add_constant(Value::Function(function)).and_then(|idx| {
+        u8::try_from(idx).map_err(|_| String::from("Too many constants in one chunk"))
+    });
+    match idx {
+        Ok(idx) => {
+            chunk.write(OpCode::Closure, ctx.pp.previous.line);
+            chunk.write_u8(idx, ctx.pp.previous.line);
+        },
+        Err(msg) => {
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            return;
+        },
+    }
+
+    for upvalue in compiled.upvalues.iter() {
+        chunk.write_u8(upvalue.is_local as u8, ctx.pp.previous.line);
+        chunk.write_u8(upvalue.index, ctx.pp.previous.line);
+    }
+}
+
+fn print_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    // Commas here separate values, so each one stops short of the comma operator.
+    assignment(chunk, source, ctx);
+    while match_token(TokenType::Comma, source, ctx) {
+        chunk.write(OpCode::PrintElement, ctx.pp.previous.line);
+        assignment(chunk, source, ctx);
+    }
+    consume(TokenType::Semicolon, "Expect ';' after value.", source, ctx);
+    chunk.write(OpCode::Print, ctx.pp.previous.line);
+}
+
+/// `eprint value;` writes to the error sink instead of the output, for diagnostics.
+fn eprint_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    expression(chunk, source, ctx);
+    consume(TokenType::Semicolon, "Expect ';' after value.", source, ctx);
+    chunk.write(OpCode::EPrint, ctx.pp.previous.line);
+}
+
+fn expression_statement(chunk: &mut Chunk, source: &str, ctx: &mut CompilerContext) {
+    expression(chunk, source, ctx);
+    consume(TokenType::Semicolon, "Expect ';' after expression.", source, ctx);
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+}
+
+fn expression(
+    chunk: &mut Chunk, 
+    source: &str, 
+    ctx: &mut CompilerContext
+) {
+    parse_precedence(Precedence::Comma, chunk, source, ctx);
+}
+
+/// Parses an expression that stops at a comma, for comma-separated lists.
+fn assignment(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    parse_precedence(Precedence::Assignment, chunk, source, ctx);
+}
+
+fn variable(
+    chunk: &mut Chunk, 
+    source: &str, 
+    ctx: &mut CompilerContext
+) {
+    let name = ctx.pp.previous.lexeme(source);
+    named_variable(name, ctx.pp.previous.line, ctx.can_assign, chunk, source, ctx);
+}
+
+fn named_variable(
+    name: &str,
+    line: u32,
+    can_assign: bool,
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    let resolved = match ctx.fc.resolve_local(name) {
+        Ok(Some(slot)) => Ok(Some((slot, OpCode::GetLocal, OpCode::SetLocal))),
+        Ok(None) => ctx.fc
+            .resolve_upvalue(name)
+            .map(|upvalue| upvalue.map(|slot| (slot, OpCode::GetUpvalue, OpCode::SetUpvalue))),
+        Err(msg) => Err(msg),
+    };
+
+    match resolved {
+        Ok(Some((slot, get_op, set_op))) => {
+            if can_assign && match_token(TokenType::Equal, source, ctx) {
+                assignment(chunk, source, ctx);
+                chunk.write(set_op, ctx.pp.previous.line);
+                chunk.write_u8(slot, ctx.pp.previous.line);
+                ctx.assignment_end = Some(chunk.code_size());
+            } else if let Some(op) = match_increment(name, source, ctx) {
+                let line = ctx.pp.previous.line;
+                chunk.write(get_op, line);
+                chunk.write_u8(slot, line);
+                chunk.write(get_op, line);
+                chunk.write_u8(slot, line);
+                emit_increment(op, line, chunk);
+                chunk.write(set_op, line);
+                chunk.write_u8(slot, line);
+                chunk.write(OpCode::Pop, line);
+            } else {
+                chunk.write(get_op, ctx.pp.previous.line);
+                chunk.write_u8(slot, ctx.pp.previous.line);
+            }
+            return;
+        },
+        Ok(None) => (),
+        Err(msg) => {
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            return;
+        },
+    }
+
+    let arg = identifier_constant(name, line, chunk);
+
+    match arg {
+        Ok(arg) => {
+            if can_assign && match_token(TokenType::Equal, source, ctx) {
+                assignment(chunk, source, ctx);
+                emit_global(OpCode::SetGlobal, &arg, ctx.pp.previous.line, chunk, ctx);
+                ctx.assignment_end = Some(chunk.code_size());
+            } else if let Some(op) = match_increment(name, source, ctx) {
+                // Global ops pop the name first, so it's pushed again for the second read and the write.
+                let line = ctx.pp.previous.line;
+                emit_global(OpCode::GetGlobal, &arg, line, chunk, ctx);
+                for _ in 0..2 {
+                    if let Err(msg) = chunk.emit_string(name, line) {
+                        error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+                    }
+                }
+                emit_global(OpCode::GetGlobal, &arg, line, chunk, ctx);
+                emit_increment(op, line, chunk);
+                emit_global(OpCode::SetGlobal, &arg, line, chunk, ctx);
+                chunk.write(OpCode::Pop, line);
+            } else {
+                emit_global(OpCode::GetGlobal, &arg, ctx.pp.previous.line, chunk, ctx);
+            }
+        },
+        Err(msg) => error_at(&ctx.pp.previous, &msg, &mut ctx.ps),
+    }
+}
+
+/// Matches a `++` or `--` after the variable `name` and returns the opcode that applies it.
+/// `this` and `super` are read through `named_variable` too, but they can't be changed.
+fn match_increment(name: &str, source: &str, ctx: &mut CompilerContext) -> Option<OpCode> {
+    if matches!(name, "this" | "super") {
+        return None;
+    }
+
+    if match_token(TokenType::PlusPlus, source, ctx) {
+        Some(OpCode::Add)
+    } else if match_token(TokenType::MinusMinus, source, ctx) {
+        Some(OpCode::Subtract)
+    } else {
+        None
+    }
+}
+
+/// Adds or subtracts one from the value on top of the stack. `i++` leaves the old value below it.
+fn emit_increment(op: OpCode, line: u32, chunk: &mut Chunk) {
+    chunk.write(OpCode::Int, line);
+    chunk.write_u8(1, line);
+    chunk.write(op, line);
+}
+
+/// A `++` or `--` that `named_variable` didn't take follows something that isn't a variable.
+fn invalid_increment(
+    _chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    error_at_token(&ctx.pp.previous, "Invalid increment target.", source, &mut ctx.ps);
+}
+
+fn this(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    if ctx.classes.is_empty() {
+        error_at(&ctx.pp.previous, "Can't use 'this' outside of a class.", &mut ctx.ps);
+        return;
+    }
+
+    named_variable("this", ctx.pp.previous.line, false, chunk, source, ctx);
+}
+
+fn super_(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    match ctx.classes.last() {
+        None => error_at(&ctx.pp.previous, "Can't use 'super' outside of a class.", &mut ctx.ps),
+        Some(class) if !class.has_superclass => {
+            error_at(&ctx.pp.previous, "Can't use 'super' in a class with no superclass.", &mut ctx.ps);
+        },
+        Some(_) => (),
+    }
+
+    consume(TokenType::Dot, "Expect '.' after 'super'.", source, ctx);
+    consume(TokenType::Identifier, "Expect superclass method name.", source, ctx);
+
+    let lexeme = ctx.pp.previous.lexeme(source);
+    let name = match chunk.add_or_retrieve_string_literal(lexeme) {
+        Ok(name) => name,
+        Err(msg) => {
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            return;
+        },
+    };
+
+    let line = ctx.pp.previous.line;
+    named_variable("this", line, false, chunk, source, ctx);
+    if match_token(TokenType::LeftParen, source, ctx) {
+        let arg_count = argument_list(chunk, source, ctx);
+        named_variable("super", line, false, chunk, source, ctx);
+        chunk.write(OpCode::SuperInvoke, ctx.pp.previous.line);
+        emit_string_id(&name, ctx.pp.previous.line, chunk, ctx);
+        chunk.write_u8(arg_count, ctx.pp.previous.line);
+    } else {
+        named_variable("super", line, false, chunk, source, ctx);
+        chunk.write(OpCode::GetSuper, ctx.pp.previous.line);
+        emit_string_id(&name, ctx.pp.previous.line, chunk, ctx);
+    }
+}
+
+fn string(
+    chunk: &mut Chunk, 
+    source: &str, 
+    ctx: &mut CompilerContext
+) {
+    let lexeme = ctx.pp.previous.lexeme(source);
+    let quotes = if lexeme.len() >= 6 && lexeme.starts_with("\"\"\"") { 3 } else { 1 };
+    let string = &lexeme[quotes..lexeme.len() - quotes];
+
+    if let Err(msg) = chunk.emit_string(string, ctx.pp.previous.line) {
+        error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+    }
+}
+
+fn number(
+    chunk: &mut Chunk, 
+    source: &str, 
+    ctx: &mut CompilerContext
+) {
+    let number = ctx.pp.previous.lexeme(source);
+    let number = number.parse::<f64>().unwrap();
+    if !number.is_finite() {
+        error_at(&ctx.pp.previous, "Number literal out of range.", &mut ctx.ps);
+        return;
+    }
+
+    // Small integers are encoded inline and don't take a constant slot.
+    if number.fract() == 0.0 && (0.0..=u8::MAX as f64).contains(&number) {
+        chunk.write(OpCode::Int, ctx.pp.previous.line);
+        chunk.write_u8(number as u8, ctx.pp.previous.line);
+        return;
+    }
+
+    if let Err(msg) = chunk.write_constant(Value::Number(number), ctx.pp.previous.line) {
+        error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+    }
+}
+
+fn grouping(
+    chunk: &mut Chunk,
+    source: &str, 
+    ctx: &mut CompilerContext
+) {
+    expression(chunk, source, ctx);
+    consume(TokenType::RightParen, "Expect ')' after expression.", source, ctx);
+    ctx.assignment_end = None;
+}
+
+fn call(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    let paren = ctx.pp.previous.clone();
+    let arg_count = argument_list(chunk, source, ctx);
+    chunk.write(OpCode::Call, ctx.pp.previous.line);
+    record_span(chunk.code_size() - 1, &paren, chunk, ctx);
+    chunk.write_u8(arg_count, ctx.pp.previous.line);
+}
+
+fn comma(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    // Only the last operand's value is kept.
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+    parse_precedence(Precedence::Assignment, chunk, source, ctx);
+}
+
+fn argument_list(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) -> u8 {
+    let mut arg_count: usize = 0;
+    if !check(TokenType::RightParen, &ctx.pp) {
+        loop {
+            assignment(chunk, source, ctx);
+            if arg_count == u8::MAX as usize {
+                error_at(&ctx.pp.previous, "Can't have more than 255 arguments.", &mut ctx.ps);
+            }
+            arg_count += 1;
+
+            // A trailing comma before `)` is allowed, as in `f(a, b,)`.
+            if !match_token(TokenType::Comma, source, ctx) || check(TokenType::RightParen, &ctx.pp) {
+                break;
+            }
+        }
+    }
+    consume(TokenType::RightParen, "Expect ')' after arguments.", source, ctx);
+
+    arg_count as u8
+}
+
+fn list_literal(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    let mut count: usize = 0;
+    if !check(TokenType::RightBracket, &ctx.pp) {
+        loop {
+            assignment(chunk, source, ctx);
+            if count == u8::MAX as usize {
+                error_at(&ctx.pp.previous, "Can't have more than 255 items in a list literal.", &mut ctx.ps);
+            }
+            count += 1;
+
+            if !match_token(TokenType::Comma, source, ctx) || check(TokenType::RightBracket, &ctx.pp) {
+                break;
+            }
+        }
+    }
+    consume(TokenType::RightBracket, "Expect ']' after list items.", source, ctx);
+
+    chunk.write(OpCode::BuildList, ctx.pp.previous.line);
+    chunk.write_u8(count as u8, ctx.pp.previous.line);
+}
+
+/// Compiles a map literal like `{"a": 1, "b": 2}`. Statements take a `{` as a block,
+/// so this only runs where an expression is expected.
+/// A `{` in expression position opens a map literal when it's empty or starts with a name or
+/// literal followed by `:`, and a block expression otherwise. A name, `:` and a loop is a
+/// labeled loop in a block expression.
+fn brace(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    if is_map_literal(source, ctx) {
+        map_literal(chunk, source, ctx);
+    } else {
+        block_expression(chunk, source, ctx);
+    }
+}
+
+fn is_map_literal(source: &str, ctx: &CompilerContext) -> bool {
+    // Scan ahead on copies, so nothing is consumed.
+    let mut sp = ctx.sp.clone();
+    let mut line = ctx.line;
+    let first = ctx.pp.current.token_type;
+
+    match first {
+        TokenType::RightBrace => true,
+        TokenType::Identifier | TokenType::String | TokenType::Number |
+        TokenType::True | TokenType::False | TokenType::Nil => {
+            if scan_token(source, &mut sp, &mut line).token_type != TokenType::Colon {
+                return false;
+            }
+            let after = scan_token(source, &mut sp, &mut line).token_type;
+            first != TokenType::Identifier || !matches!(after, TokenType::While | TokenType::Do | TokenType::For)
+        },
+        _ => false,
+    }
+}
+
+/// `{ statements; value }` evaluates to its trailing expression, or nil when it ends with a
+/// statement. It's a scope of the enclosing function, so `break`, `continue` and `return`
+/// inside it work as in a statement block. Its locals go on the stack above the values the
+/// enclosing expression has pushed so far, which get hidden locals of their own so the slots
+/// line up, and the result takes the slot right below the block's first local.
+fn block_expression(
+    chunk: &mut Chunk,
+    source: &str,
     ctx: &mut CompilerContext
 ) {
-    let operator_type = ctx.pp.previous.token_type;
+    let temporaries = temporaries(chunk, ctx);
+    let outer_locals = ctx.fc.locals.len();
+    // A local whose initializer holds this block has nothing on the stack yet,
+    // so the first value pushed goes in its slot.
+    let pending = ctx.fc.locals.last().is_some_and(|local| local.depth.is_none());
+    let result_slot = outer_locals - pending as usize + temporaries;
+    if result_slot >= MAX_LOCALS {
+        error_at(&ctx.pp.previous, "Too many local variables in function.", &mut ctx.ps);
+        return;
+    }
+
+    chunk.write(OpCode::Nil, ctx.pp.previous.line);
+    begin_scope(ctx);
+    // They belong to the block's scope, so a `break` out of it pops them too.
+    while ctx.fc.locals.len() <= result_slot {
+        let depth = Some(ctx.fc.scope_depth);
+        ctx.fc.locals.push(Local { name: String::from(" temporary"), depth, is_captured: false });
+    }
+    mark_stack(0, chunk, ctx);
+
+    loop {
+        if check(TokenType::RightBrace, &ctx.pp) || check(TokenType::EOF, &ctx.pp) {
+            break;
+        }
+
+        let labeled = check(TokenType::Identifier, &ctx.pp) && peek_next(source, ctx) == TokenType::Colon;
+        if labeled || starts_statement(ctx.pp.current.token_type) {
+            declaration(chunk, source, ctx);
+            continue;
+        }
+
+        expression(chunk, source, ctx);
+        if !match_token(TokenType::Semicolon, source, ctx) {
+            chunk.write(OpCode::SetLocal, ctx.pp.previous.line);
+            chunk.write_u8(result_slot as u8, ctx.pp.previous.line);
+            chunk.write(OpCode::Pop, ctx.pp.previous.line);
+            break;
+        }
+        chunk.write(OpCode::Pop, ctx.pp.previous.line);
+    }
+    consume(TokenType::RightBrace, "Expect '}' after block expression.", source, ctx);
+
+    // Like `end_scope`, but the hidden locals and the result stay on the stack.
+    ctx.fc.scope_depth -= 1;
+    while ctx.fc.locals.len() > result_slot + 1 {
+        if ctx.fc.locals.last().is_some_and(|local| local.is_captured) {
+            chunk.write(OpCode::CloseUpvalue, ctx.pp.previous.line);
+        } else {
+            chunk.write(OpCode::Pop, ctx.pp.previous.line);
+        }
+        ctx.fc.locals.pop();
+    }
+    ctx.fc.locals.truncate(outer_locals);
+    mark_stack(temporaries + 1, chunk, ctx);
+}
+
+/// Whether a declaration or statement other than an expression statement starts with `token_type`.
+fn starts_statement(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::Print | TokenType::EPrint | TokenType::Return |
+        TokenType::Del | TokenType::Do | TokenType::If | TokenType::While | TokenType::For | TokenType::Import |
+        TokenType::Throw | TokenType::Try | TokenType::Break | TokenType::Continue
+    )
+}
+
+fn map_literal(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    let mut count: usize = 0;
+    if !check(TokenType::RightBrace, &ctx.pp) {
+        loop {
+            assignment(chunk, source, ctx);
+            consume(TokenType::Colon, "Expect ':' after map key.", source, ctx);
+            assignment(chunk, source, ctx);
+            if count == u8::MAX as usize {
+                error_at(&ctx.pp.previous, "Can't have more than 255 entries in a map literal.", &mut ctx.ps);
+            }
+            count += 1;
+
+            if !match_token(TokenType::Comma, source, ctx) || check(TokenType::RightBrace, &ctx.pp) {
+                break;
+            }
+        }
+    }
+    consume(TokenType::RightBrace, "Expect '}' after map entries.", source, ctx);
+
+    chunk.write(OpCode::BuildMap, ctx.pp.previous.line);
+    chunk.write_u8(count as u8, ctx.pp.previous.line);
+}
+
+fn subscript(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    // The index expression overwrites `ctx.can_assign`.
+    let can_assign = ctx.can_assign;
+    let bracket = ctx.pp.previous.clone();
+    expression(chunk, source, ctx);
+    consume(TokenType::RightBracket, "Expect ']' after index.", source, ctx);
+
+    if can_assign && match_token(TokenType::Equal, source, ctx) {
+        assignment(chunk, source, ctx);
+        chunk.write(OpCode::SetIndex, ctx.pp.previous.line);
+        ctx.assignment_end = Some(chunk.code_size());
+    } else {
+        chunk.write(OpCode::GetIndex, ctx.pp.previous.line);
+    }
+    record_span(chunk.code_size() - 1, &bracket, chunk, ctx);
+}
+
+fn dot(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    consume(TokenType::Identifier, "Expect property name after '.'.", source, ctx);
+
+    let name_token = ctx.pp.previous.clone();
+    let lexeme = name_token.lexeme(source);
+    let name = match chunk.add_or_retrieve_string_literal(lexeme) {
+        Ok(name) => name,
+        Err(msg) => {
+            error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
+            return;
+        },
+    };
+
+    if ctx.can_assign && match_token(TokenType::Equal, source, ctx) {
+        assignment(chunk, source, ctx);
+        chunk.write(OpCode::SetProperty, ctx.pp.previous.line);
+        record_span(chunk.code_size() - 1, &name_token, chunk, ctx);
+        emit_string_id(&name, ctx.pp.previous.line, chunk, ctx);
+        ctx.assignment_end = Some(chunk.code_size());
+    } else if match_token(TokenType::LeftParen, source, ctx) {
+        // Calling a method right away skips creating a bound method.
+        let arg_count = argument_list(chunk, source, ctx);
+        chunk.write(OpCode::Invoke, ctx.pp.previous.line);
+        record_span(chunk.code_size() - 1, &name_token, chunk, ctx);
+        emit_string_id(&name, ctx.pp.previous.line, chunk, ctx);
+        chunk.write_u8(arg_count, ctx.pp.previous.line);
+    } else {
+        chunk.write(OpCode::GetProperty, ctx.pp.previous.line);
+        record_span(chunk.code_size() - 1, &name_token, chunk, ctx);
+        emit_string_id(&name, ctx.pp.previous.line, chunk, ctx);
+    }
+}
+
+fn lambda(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    function(FunctionType::Function, None, chunk, source, ctx);
+}
+
+fn unary(
+    chunk: &mut Chunk,
+    source: &str, 
+    ctx: &mut CompilerContext
+) {
+    let operator = ctx.pp.previous.clone();
+    let operator_type = operator.token_type;
+
+    parse_precedence(Precedence::Unary, chunk, source, ctx);
+
+    let offset = chunk.code_size();
+    match operator_type {
+        TokenType::Bang => chunk.write(OpCode::Not, ctx.pp.previous.line),
+        TokenType::Minus => chunk.write(OpCode::Negate, ctx.pp.previous.line),
+        TokenType::TypeOf => chunk.write(OpCode::TypeOf, ctx.pp.previous.line),
+        _ => panic!("Unknown unary operator: {:?}", operator_type),
+    }
+    record_span(offset, &operator, chunk, ctx);
+}
+
+fn binary(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    let operator = ctx.pp.previous.clone();
+    let operator_type = operator.token_type;
     let rule = ParseRule::query(operator_type);
     let precedence = Precedence::next_higher_precedence(&rule.precedence);
 
     parse_precedence(precedence, chunk, source, ctx);
 
+    // `a < b < c` would compare a bool against `c` at runtime.
+    if is_comparison(operator_type) && is_comparison(ctx.pp.current.token_type) {
+        error_at_token(&ctx.pp.current, "Chained comparison is not allowed; use 'and'.", source, &mut ctx.ps);
+    }
+
+    // The span goes on the first instruction, which is the one that can fail.
+    let offset = chunk.code_size();
     match operator_type {
         TokenType::BangEqual => {
             chunk.write(OpCode::Equal, ctx.pp.previous.line);
@@ -406,11 +1970,62 @@ fn binary(
         TokenType::Slash => chunk.write(OpCode::Divide, ctx.pp.previous.line),
         _ => panic!("Unknown binary operator: {:?}", operator_type),
     }
+    record_span(offset, &operator, chunk, ctx);
+}
+
+fn is_comparison(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual
+    )
+}
+
+fn and_(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    let end_jump = emit_jump(OpCode::JumpIfFalse, chunk, ctx);
+
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+    parse_precedence(Precedence::And, chunk, source, ctx);
+
+    patch_jump(end_jump, chunk, ctx);
+}
+
+fn or_(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    let else_jump = emit_jump(OpCode::JumpIfFalse, chunk, ctx);
+    let end_jump = emit_jump(OpCode::Jump, chunk, ctx);
+
+    patch_jump(else_jump, chunk, ctx);
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+
+    parse_precedence(Precedence::Or, chunk, source, ctx);
+    patch_jump(end_jump, chunk, ctx);
+}
+
+/// `a ?? b` is `a` unless it's nil, and only then evaluates `b`. It groups to the right,
+/// so `a ?? b ?? c` stops at the first operand that isn't nil.
+fn coalesce(
+    chunk: &mut Chunk,
+    source: &str,
+    ctx: &mut CompilerContext
+) {
+    let end_jump = emit_jump(OpCode::JumpIfNotNil, chunk, ctx);
+
+    chunk.write(OpCode::Pop, ctx.pp.previous.line);
+    parse_precedence(Precedence::Coalesce, chunk, source, ctx);
+
+    patch_jump(end_jump, chunk, ctx);
 }
 
 fn literal(
     chunk: &mut Chunk,
-    _: &String, 
+    _: &str, 
     ctx: &mut CompilerContext
 ) {
     match ctx.pp.previous.token_type {
@@ -424,58 +2039,61 @@ fn literal(
 fn parse_precedence(
     precedence: Precedence,
     chunk: &mut Chunk,
-    source: &String, 
+    source: &str, 
     ctx: &mut CompilerContext
 ) {
     advance(source, ctx);
+    let start = ctx.pp.previous.start;
     let prefix_rule = ParseRule::query(ctx.pp.previous.token_type).prefix;
     let prefix_rule = match prefix_rule {
         Some(rule) => rule,
         None => {
-            error_at(ctx.pp.previous.line, "Expect expression.", &mut ctx.ps);
+            error_at(&ctx.pp.previous, "Expect expression.", &mut ctx.ps);
             return;
         }
     };
 
-    ctx.can_assign = precedence <= Precedence::Assignment;
+    // Nested expressions overwrite `ctx.can_assign`, so restore it before every infix rule.
+    let can_assign = precedence <= Precedence::Assignment;
+    ctx.can_assign = can_assign;
     prefix_rule(chunk, source, ctx);
 
     while precedence <= ParseRule::query(ctx.pp.current.token_type).precedence {
         advance(source, ctx);
         let infix_rule = ParseRule::query(ctx.pp.previous.token_type).infix.unwrap();
+        ctx.can_assign = can_assign;
         infix_rule(chunk, source, ctx);
     }
 
-    if ctx.can_assign && match_token(TokenType::Equal, source, ctx) {
-        error_at(ctx.pp.previous.line, "Invalid assignment target.", &mut ctx.ps);
+    let end = ctx.pp.previous.start + ctx.pp.previous.length;
+    if can_assign && match_token(TokenType::Equal, source, ctx) {
+        // `previous` is now the `=`, so the error points at it.
+        let msg = format!("Invalid assignment target '{}'.", &source[start..end]);
+        error_at(&ctx.pp.previous, &msg, &mut ctx.ps);
     }
 }
 
 fn advance(
-    source: &String, 
+    source: &str, 
     ctx: &mut CompilerContext
 ) {
     ctx.pp.previous = ctx.pp.current.clone();
     
     loop {
-        let result = scan_token(source, &mut ctx.sp, &mut ctx.line);
-
-        match result {
-            Ok(token) => {
-                ctx.pp.current = token;
-                break;
-            }
-            Err(err) => {
-                error_at(err.line, &err.message, &mut ctx.ps);
-            }
+        ctx.pp.current = scan_token(source, &mut ctx.sp, &mut ctx.line);
+        if ctx.pp.current.token_type != TokenType::Error {
+            break;
         }
+
+        let token = &ctx.pp.current;
+        error_at(token, token.message.as_deref().unwrap_or_default(), &mut ctx.ps);
     }
 }
 
 fn consume(
     token_type: TokenType,
     message: &str,
-    source: &String, 
+    source: &str, 
     ctx: &mut CompilerContext
 ) {
     if ctx.pp.current.token_type == token_type {
@@ -483,10 +2101,27 @@ fn consume(
         return;
     }
 
-    error_at(ctx.line, message, &mut ctx.ps);
+    error_at_token(&ctx.pp.current, message, source, &mut ctx.ps);
+}
+
+fn error_at(token: &Token, message: &str, ps: &mut ParserState) {
+    report_error(token, "", message, ps);
+}
+
+/// Reports an error on `token`'s line and says what was found there,
+/// like `[line 3] Error at 'else': Expect ';' after value.`
+fn error_at_token(token: &Token, message: &str, source: &str, ps: &mut ParserState) {
+    let location = match token.token_type {
+        TokenType::EOF => String::from(" at end"),
+        // The scanner's message already describes the bad token.
+        TokenType::Error => String::new(),
+        _ => format!(" at '{}'", token.lexeme(source)),
+    };
+
+    report_error(token, &location, message, ps);
 }
 
-fn error_at(line: u32, message: &str, ps: &mut ParserState) {
+fn report_error(token: &Token, location: &str, message: &str, ps: &mut ParserState) {
     if ps.panic_mode {
         return;
     }
@@ -494,5 +2129,54 @@ fn error_at(line: u32, message: &str, ps: &mut ParserState) {
     ps.panic_mode = true;
     ps.had_error = true;
 
-    eprintln!("[line {}] Error: {}", line, message);
+    ps.errors.push(CompileError {
+        message: String::from(message),
+        line: token.line,
+        location: String::from(location),
+        start: token.start,
+        length: token.length,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ptr::fn_addr_eq;
+
+    fn is(rule: Option<ParseFn>, expected: ParseFn) -> bool {
+        rule.is_some_and(|rule| fn_addr_eq(rule, expected))
+    }
+
+    #[test]
+    fn rules_table_matches_the_grammar() {
+        let paren = ParseRule::query(TokenType::LeftParen);
+        assert!(is(paren.prefix, grouping) && is(paren.infix, call));
+        assert!(paren.precedence == Precedence::Call);
+
+        let minus = ParseRule::query(TokenType::Minus);
+        assert!(is(minus.prefix, unary) && is(minus.infix, binary));
+        assert!(minus.precedence == Precedence::Term);
+
+        let star = ParseRule::query(TokenType::Star);
+        assert!(star.prefix.is_none() && is(star.infix, binary));
+        assert!(star.precedence == Precedence::Factor);
+
+        let less_equal = ParseRule::query(TokenType::LessEqual);
+        assert!(is(less_equal.infix, binary));
+        assert!(less_equal.precedence == Precedence::Comparison);
+
+        let identifier = ParseRule::query(TokenType::Identifier);
+        assert!(is(identifier.prefix, variable) && identifier.infix.is_none());
+        assert!(identifier.precedence == Precedence::None);
+
+        assert!(is(ParseRule::query(TokenType::Or).infix, or_));
+        assert!(ParseRule::query(TokenType::Or).precedence == Precedence::Or);
+        assert!(is(ParseRule::query(TokenType::QuestionQuestion).infix, coalesce));
+        assert!(is(ParseRule::query(TokenType::Nil).prefix, literal));
+
+        let semicolon = ParseRule::query(TokenType::Semicolon);
+        assert!(semicolon.prefix.is_none() && semicolon.infix.is_none());
+        assert!(ParseRule::query(TokenType::EOF).precedence == Precedence::None);
+    }
 }
@@ -1,21 +1,127 @@
+pub mod chunk;
+pub mod compiler;
+pub mod scanner;
+pub mod object;
+pub mod value;
+pub mod vm;
+
 use std::fs::read_to_string;
+use std::io::{self, BufRead, Read, Write};
 use std::process::exit;
 
-use crate::lox::vm::{VM, InterpretResult};
+use crate::lox::chunk::Chunk;
+use crate::lox::compiler::{compile, compile_file_into, CompileError, CompileOptions};
+use crate::lox::vm::{VM, InterpretResult, RunFilesError};
+
+/// The crate version, for `--version` and the header of serialized chunks.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub fn run_file(vm: &VM, path: &str) {
+/// Compiles `source` without running it, so the chunk can be inspected or disassembled.
+pub fn compile_only(source: &str) -> Result<Chunk, Vec<CompileError>> {
+    compile(source)
+}
+
+pub fn run_file(vm: &mut VM, path: &str) {
     let source = read_to_string(path).expect("Failed to read file");
-    let result = vm.interpret(&source);
+    exit_on_failure(vm.interpret_file(&source, path));
+}
 
-    match result {
-        InterpretResult::Ok => {}
-        InterpretResult::CompileError => {
-            println!("Compile error");
+/// Runs several scripts in order with shared globals, exiting with the failing file's status.
+pub fn run_files(vm: &mut VM, paths: &[&str]) {
+    match vm.run_files(paths) {
+        Ok(()) => {},
+        Err(RunFilesError::Read { path, message }) => {
+            eprintln!("Failed to read '{}': {}", path, message);
+            exit(74);
+        },
+        Err(RunFilesError::Interpret { path, result }) => {
+            eprintln!("Error in '{}'", path);
+            exit_on_failure(result);
+        },
+    }
+}
+
+/// Prints the disassembly of the script at `path` without running it.
+pub fn dump_file(path: &str) {
+    let source = read_to_string(path).expect("Failed to read file");
+
+    let mut chunk = Chunk::new();
+    match compile_file_into(&source, path, &mut chunk, CompileOptions::default()) {
+        Ok(_) => print!("{}", chunk.disassemble_to_string(path)),
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
             exit(65);
+        },
+    }
+}
+
+/// Runs the whole of stdin as one script, for `clox-rs -`. Empty input does nothing.
+pub fn run_stdin(vm: &mut VM) {
+    let mut source = String::new();
+    io::stdin().read_to_string(&mut source).expect("Failed to read stdin");
+    run_source(vm, &source);
+}
+
+fn run_source(vm: &mut VM, source: &str) {
+    exit_on_failure(vm.interpret(source));
+}
+
+/// The process exit status for `result`, following the sysexits convention used by clox:
+/// 0 on success, 65 for compile errors and 70 for runtime errors and interrupts.
+pub fn exit_code(result: &InterpretResult) -> i32 {
+    match result {
+        InterpretResult::Ok => 0,
+        InterpretResult::CompileError => 65,
+        InterpretResult::RuntimeError(_) | InterpretResult::Interrupted => 70,
+    }
+}
+
+fn exit_on_failure(result: InterpretResult) {
+    match result {
+        InterpretResult::Ok => return,
+        InterpretResult::CompileError => println!("Compile error"),
+        InterpretResult::RuntimeError(_) => println!("Runtime error"),
+        InterpretResult::Interrupted => println!("Interrupted"),
+    }
+
+    exit(exit_code(&result));
+}
+
+/// Reads lines from `input` and runs each one, writing the prompt and meta-command
+/// output to `output`. Scripts still print through the VM's own output sink.
+pub fn repl(vm: &mut VM, mut input: impl BufRead, mut output: impl Write) {
+    loop {
+        write!(output, "> ").expect("Failed to write prompt");
+        output.flush().expect("Failed to flush output");
+
+        let mut line = String::new();
+        if input.read_line(&mut line).expect("Failed to read line") == 0 {
+            writeln!(output).expect("Failed to write output");
+            break;
         }
-        InterpretResult::RuntimeError => {
-            println!("Runtime error");
-            exit(70);
+
+        // Meta-commands are handled here and never reach the scanner.
+        if let Some(command) = line.trim().strip_prefix(':') {
+            if !meta_command(vm, command, &mut output) {
+                break;
+            }
+            continue;
         }
+
+        vm.interpret(&line);
     }
 }
+
+/// Runs a REPL `:command`. Returns `false` when the REPL should exit.
+fn meta_command(vm: &mut VM, command: &str, output: &mut impl Write) -> bool {
+    match command {
+        "quit" => return false,
+        "reset" => vm.reset(),
+        "dump" => write!(output, "{}", vm.chunk().disassemble_to_string("repl")).expect("Failed to write output"),
+        _ => writeln!(output, "Unknown command ':{}'. Available commands: :quit, :reset, :dump", command).expect("Failed to write output"),
+    }
+
+    true
+}
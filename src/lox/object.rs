@@ -1,20 +1,32 @@
+use crate::lox::value::Value;
+use crate::lox::vm::NativeFn;
+
+use core::cmp::Ordering;
 use core::fmt::Display;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-const MAX_STRING_LITERAL: u8 = u8::MAX;
+/// Literal ids below this fit the 24-bit operand of the long instructions. Dynamic ids start here.
+const MAX_STRING_LITERAL: u64 = 1 << 24;
+const DYNAMIC_STRING_INITIAL_BYTES: usize = 4096;
+const DYNAMIC_STRING_INITIAL_COUNT: usize = 64;
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct StringId(pub u64);
 
 impl StringId {
     pub fn is_literal(&self) -> bool {
-        self.0 < MAX_STRING_LITERAL as u64
+        self.0 < MAX_STRING_LITERAL
     }
 
     pub fn new_literal_id(id: u8) -> StringId {
         StringId(id as u64)
     }
 
+    /// For literal ids read from a 24-bit operand.
+    pub fn new_long_literal_id(id: usize) -> StringId {
+        StringId(id as u64)
+    }
+
     pub fn new_dynamic_id(id: u64) -> StringId {
         StringId(id)
     }
@@ -26,22 +38,24 @@ impl Display for StringId {
     }
 }
 
+#[derive(Clone)]
 struct StringData {
     start: usize,
     end: usize,
 }
 
+#[derive(Clone)]
 pub struct StringLiteralStorage {
     string: String,
     data: Vec<StringData>,
-    next_id: u8,
+    next_id: u64,
 }
 
 impl StringLiteralStorage {
     pub fn new() -> StringLiteralStorage {
         StringLiteralStorage {
             string: String::new(),
-            data: Vec::new(),
+            data: Vec::with_capacity(u8::MAX as usize),
             next_id: 0,
         }
     }
@@ -70,7 +84,7 @@ impl StringLiteralStorage {
 
         self.next_id += 1;
 
-        Ok(StringId(id as u64))
+        Ok(StringId(id))
     }
 
     pub fn get_string(&self, StringId(id): &StringId) -> &str {
@@ -78,11 +92,28 @@ impl StringLiteralStorage {
         &self.string[l.start..l.end]
     }
 
+    /// Like `get_string`, but `None` for ids this storage never handed out.
+    pub fn try_get_string(&self, StringId(id): &StringId) -> Option<&str> {
+        let l = self.data.get(*id as usize)?;
+        Some(&self.string[l.start..l.end])
+    }
+
     pub fn is_max_string(&self) -> bool {
-        self.next_id as u8 == MAX_STRING_LITERAL
-    } 
+        self.next_id == MAX_STRING_LITERAL
+    }
+
+    pub fn count(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl Default for StringLiteralStorage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+#[derive(Clone)]
 pub struct DynamicStringStorage {
     string: String,
     data: HashMap<u64, StringData>,
@@ -92,9 +123,9 @@ pub struct DynamicStringStorage {
 impl DynamicStringStorage {
     pub fn new() -> DynamicStringStorage {
         DynamicStringStorage {
-            string: String::new(),
-            data: HashMap::new(),
-            next_id: MAX_STRING_LITERAL as u64,
+            string: String::with_capacity(DYNAMIC_STRING_INITIAL_BYTES),
+            data: HashMap::with_capacity(DYNAMIC_STRING_INITIAL_COUNT),
+            next_id: MAX_STRING_LITERAL,
         }
     }
 
@@ -116,7 +147,215 @@ impl DynamicStringStorage {
     }
 
     pub fn get_string(&self, StringId(id): &StringId) -> &str {
-        let l = self.data.get(&id).unwrap();
+        let l = self.data.get(id).unwrap();
         &self.string[l.start..l.end]
     }
+
+    /// Number of bytes held by live strings.
+    pub fn size(&self) -> usize {
+        self.string.len()
+    }
+
+    /// Drops every string that isn't marked and compacts the backing buffer.
+    /// Ids of the surviving strings don't change.
+    pub fn sweep(&mut self, marked: &HashSet<StringId>) {
+        self.data.retain(|id, _| marked.contains(&StringId(*id)));
+
+        let old = std::mem::take(&mut self.string);
+        for l in self.data.values_mut() {
+            let start = self.string.len();
+            self.string.push_str(&old[l.start..l.end]);
+            l.start = start;
+            l.end = self.string.len();
+        }
+    }
+
+    /// Drops every string but keeps the allocated buffers.
+    pub fn clear(&mut self) {
+        self.string.clear();
+        self.data.clear();
+        self.next_id = MAX_STRING_LITERAL;
+    }
+}
+
+impl Default for DynamicStringStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub name: Option<StringId>,
+    pub arity: u8,
+    pub entry: usize,
+    pub upvalue_count: usize,
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "function: {}", self.entry)
+    }
+}
+
+/// Arity of a native that takes any number of arguments and checks them itself.
+pub const VARIADIC: u8 = u8::MAX;
+
+/// A function implemented in Rust and exposed to scripts as a global.
+#[derive(Clone, Copy, Debug)]
+pub struct Native {
+    pub name: &'static str,
+    /// Number of arguments, or `VARIADIC`.
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl Display for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "native: {}", self.name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ObjectId(pub u64);
+
+impl Display for ObjectId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "object: {}", self.0)
+    }
+}
+
+pub struct Closure {
+    pub function: Function,
+    pub upvalues: Vec<ObjectId>,
+}
+
+pub struct Class {
+    pub name: StringId,
+    pub methods: HashMap<StringId, ObjectId>,
+    pub initializer: Option<ObjectId>,
+}
+
+pub struct Instance {
+    pub class: ObjectId,
+    pub fields: HashMap<StringId, Value>,
+}
+
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjectId,
+}
+
+/// A map key. Strings are keyed by their contents, so a literal and a dynamic string
+/// with the same text find the same entry.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ValueKey {
+    Nil,
+    Bool(bool),
+    /// The number's bits. NaN is never a key, and `-0` is stored as `0` since they're equal.
+    Number(u64),
+    String(String),
+}
+
+impl ValueKey {
+    pub fn number(n: f64) -> Option<ValueKey> {
+        if n.is_nan() {
+            return None;
+        }
+
+        Some(ValueKey::Number(if n == 0.0 { 0.0f64.to_bits() } else { n.to_bits() }))
+    }
+}
+
+/// Nil first, then bools, then numbers by value, then strings, so maps print in a stable order.
+impl Ord for ValueKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (ValueKey::Nil, ValueKey::Nil) => Ordering::Equal,
+            (ValueKey::Bool(a), ValueKey::Bool(b)) => a.cmp(b),
+            (ValueKey::Number(a), ValueKey::Number(b)) => f64::from_bits(*a).total_cmp(&f64::from_bits(*b)),
+            (ValueKey::String(a), ValueKey::String(b)) => a.cmp(b),
+            (ValueKey::Nil, _) => Ordering::Less,
+            (_, ValueKey::Nil) => Ordering::Greater,
+            (ValueKey::Bool(_), _) => Ordering::Less,
+            (_, ValueKey::Bool(_)) => Ordering::Greater,
+            (ValueKey::Number(_), _) => Ordering::Less,
+            (_, ValueKey::Number(_)) => Ordering::Greater,
+        }
+    }
+}
+
+impl PartialOrd for ValueKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub struct List {
+    pub items: Vec<Value>,
+}
+
+/// Reading a key that isn't in the map is a runtime error rather than `nil`,
+/// so a typo in a key doesn't go unnoticed.
+pub struct Map {
+    pub entries: HashMap<ValueKey, Value>,
+}
+
+pub enum Upvalue {
+    /// Still points at a live stack slot.
+    Open(usize),
+    /// The slot went out of scope, so the upvalue owns the value now.
+    Closed(Value),
+}
+
+#[derive(Clone)]
+pub struct ObjectStorage<T> {
+    data: HashMap<u64, T>,
+    next_id: u64,
+}
+
+impl<T> ObjectStorage<T> {
+    pub fn new() -> ObjectStorage<T> {
+        ObjectStorage {
+            data: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn add(&mut self, object: T) -> ObjectId {
+        let id = self.next_id;
+        self.data.insert(id, object);
+        self.next_id += 1;
+
+        ObjectId(id)
+    }
+
+    pub fn get(&self, ObjectId(id): &ObjectId) -> &T {
+        self.data.get(id).unwrap()
+    }
+
+    pub fn get_mut(&mut self, ObjectId(id): &ObjectId) -> &mut T {
+        self.data.get_mut(id).unwrap()
+    }
+
+    /// Number of bytes held by live objects.
+    pub fn size(&self) -> usize {
+        self.data.len() * std::mem::size_of::<T>()
+    }
+
+    pub fn sweep(&mut self, marked: &HashSet<ObjectId>) {
+        self.data.retain(|id, _| marked.contains(&ObjectId(*id)));
+    }
+
+    /// Drops every object but keeps the allocated table.
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.next_id = 0;
+    }
+}
+
+impl<T> Default for ObjectStorage<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -1,21 +1,28 @@
+const BOM: char = '\u{FEFF}';
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum TokenType {
     // Single-character tokens.
-    LeftParen, RightParen, LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus, Semicolon, Slash, Star,
+    LeftParen, RightParen, LeftBrace, RightBrace, LeftBracket, RightBracket,
+    Colon, Comma, Dot, Minus, Plus, Semicolon, Slash, Star,
 
     // One or two character tokens.
     Bang, BangEqual,
     Equal, EqualEqual,
     Greater, GreaterEqual,
     Less, LessEqual,
+    MinusMinus, PlusPlus,
+    QuestionQuestion,
 
     // Literals.
     Identifier, String, Number,
 
     // Keywords.
-    And, Class, Else, False, Fun, For, If, Nil, Or,
-    Print, Return, Super, This, True, Var, While,
+    And, Break, Catch, Class, Continue, Del, Do, Else, EPrint, False, Fun, For, If, Import, In, Nil, Or,
+    Print, Return, Super, This, Throw, True, Try, TypeOf, Var, While,
+
+    /// Only produced by a pointer from `ScannerPointer::preserving_comments`.
+    Comment,
 
     Error, EOF,
 }
@@ -26,9 +33,11 @@ pub struct Token {
     pub start: usize,
     pub length: usize,
     pub line: u32,
+    /// Set only on `TokenType::Error` tokens.
+    pub message: Option<String>,
 }
 
-pub struct ErrorToken {
+struct ErrorToken {
     pub message: String,
     pub line: u32,
 }
@@ -40,6 +49,22 @@ impl Token {
             start,
             length,
             line,
+            message: None,
+        }
+    }
+
+    /// The source text this token covers. `start` and `length` are byte offsets.
+    pub fn lexeme<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start..self.start + self.length]
+    }
+
+    fn error(err: ErrorToken, pointer: &ScannerPointer) -> Token {
+        Token {
+            token_type: TokenType::Error,
+            start: pointer.start,
+            length: pointer.current - pointer.start,
+            line: err.line,
+            message: Some(err.message),
         }
     }
 }
@@ -55,9 +80,11 @@ fn make_error_token(message: &str, line: &u32) -> ErrorToken {
     }
 }
 
+#[derive(Clone)]
 pub struct ScannerPointer {
     start: usize,
     current: usize,
+    preserve_comments: bool,
 }
 
 impl ScannerPointer {
@@ -65,12 +92,89 @@ impl ScannerPointer {
         ScannerPointer {
             start: 0,
             current: 0,
+            preserve_comments: false,
+        }
+    }
+
+    /// A pointer that scans `//` comments as `TokenType::Comment` tokens instead of skipping them,
+    /// for tools like formatters that need to keep them.
+    pub fn preserving_comments() -> ScannerPointer {
+        ScannerPointer {
+            preserve_comments: true,
+            ..ScannerPointer::new()
+        }
+    }
+}
+
+impl Default for ScannerPointer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Scans the next token. Lexical errors come back as `TokenType::Error` tokens
+/// carrying the message, so callers see one uniform stream.
+pub fn scan_token(source: &str, pointer: &mut ScannerPointer, line: &mut u32) -> Token {
+    match scan(source, pointer, line) {
+        Ok(token) => token,
+        Err(err) => Token::error(err, pointer),
+    }
+}
+
+/// Scans all of `source`. The last token is always `TokenType::EOF`.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let mut pointer = ScannerPointer::new();
+    let mut line = 1;
+    let mut tokens = Vec::new();
+
+    loop {
+        let token = scan_token(source, &mut pointer, &mut line);
+        let done = token.token_type == TokenType::EOF;
+        tokens.push(token);
+        if done {
+            return tokens;
         }
     }
 }
 
-pub fn scan_token(source: &String, pointer: &mut ScannerPointer, line: &mut u32) -> Result<Token, ErrorToken> {
+/// Lays out the tokens of `source` as a table, one per row:
+///
+/// ```text
+/// line | col | type         | lexeme
+///    1 |   1 | Print        | print
+///    1 |   7 | Number       | 1
+/// ```
+///
+/// `col` counts characters from 1. Error tokens show their message instead of a lexeme.
+pub fn format_tokens(source: &str) -> String {
+    let mut table = String::from("line | col | type         | lexeme\n");
+
+    for token in tokenize(source) {
+        let line_start = source[..token.start].rfind('\n').map_or(0, |i| i + 1);
+        let col = source[line_start..token.start].chars().count() + 1;
+        let text = match token.token_type {
+            TokenType::Error => token.message.as_deref().unwrap_or_default(),
+            _ => token.lexeme(source),
+        };
+        let row = format!("{:>4} | {:>3} | {:<12} | {}", token.line, col, format!("{:?}", token.token_type), text);
+
+        table.push_str(row.trim_end());
+        table.push('\n');
+    }
+
+    table
+}
+
+fn scan(source: &str, pointer: &mut ScannerPointer, line: &mut u32) -> Result<Token, ErrorToken> {
+    // Editors on Windows like to start files with a byte order mark. Anywhere else it's an error.
+    if pointer.current == 0 && source.starts_with(BOM) {
+        pointer.current = BOM.len_utf8();
+    }
     skip_whitespace(source, pointer, line);
+    while peek(source, pointer) == '#' && is_at_line_start(source, pointer) {
+        line_directive(source, pointer, line)?;
+        skip_whitespace(source, pointer, line);
+    }
     pointer.start = pointer.current;
 
     if is_at_end(source, pointer) {
@@ -92,11 +196,35 @@ pub fn scan_token(source: &String, pointer: &mut ScannerPointer, line: &mut u32)
         ')' => Ok(make_token(TokenType::RightParen, pointer, line)),
         '{' => Ok(make_token(TokenType::LeftBrace, pointer, line)),
         '}' => Ok(make_token(TokenType::RightBrace, pointer, line)),
+        '[' => Ok(make_token(TokenType::LeftBracket, pointer, line)),
+        ']' => Ok(make_token(TokenType::RightBracket, pointer, line)),
         ';' => Ok(make_token(TokenType::Semicolon, pointer, line)),
+        ':' => Ok(make_token(TokenType::Colon, pointer, line)),
         ',' => Ok(make_token(TokenType::Comma, pointer, line)),
+        // `.5` is a number. A `.` followed by anything else is property access.
+        '.' if is_digit(peek(source, pointer)) => Ok(fraction(source, pointer, line)),
         '.' => Ok(make_token(TokenType::Dot, pointer, line)),
-        '-' => Ok(make_token(TokenType::Minus, pointer, line)),
-        '+' => Ok(make_token(TokenType::Plus, pointer, line)),
+        '-' => {
+            if match_char(source, pointer, '-') {
+                Ok(make_token(TokenType::MinusMinus, pointer, line))
+            } else {
+                Ok(make_token(TokenType::Minus, pointer, line))
+            }
+        },
+        '+' => {
+            if match_char(source, pointer, '+') {
+                Ok(make_token(TokenType::PlusPlus, pointer, line))
+            } else {
+                Ok(make_token(TokenType::Plus, pointer, line))
+            }
+        },
+        // Only reached when preserving comments; otherwise `skip_whitespace` already ate them.
+        '/' if peek(source, pointer) == '/' => {
+            while peek(source, pointer) != '\n' && !is_at_end(source, pointer) {
+                advance(source, pointer);
+            }
+            Ok(make_token(TokenType::Comment, pointer, line))
+        },
         '/' => Ok(make_token(TokenType::Slash, pointer, line)),
         '*' => Ok(make_token(TokenType::Star, pointer, line)),
         '!' => {
@@ -127,12 +255,55 @@ pub fn scan_token(source: &String, pointer: &mut ScannerPointer, line: &mut u32)
                 Ok(make_token(TokenType::Greater, pointer, line))
             }
         },
+        '?' => {
+            if match_char(source, pointer, '?') {
+                Ok(make_token(TokenType::QuestionQuestion, pointer, line))
+            } else {
+                Err(make_error_token("Unexpected character.", line))
+            }
+        },
+        '"' if peek(source, pointer) == '"' && peek_next(source, pointer) == '"' => {
+            triple_quoted_string(source, pointer, line)
+        },
         '"' => string(source, pointer, line),
         _ => Err(make_error_token("Unexpected character.", line)),
     }
 }
 
-fn identifier(source: &String, pointer: &mut ScannerPointer, line: &mut u32) -> Token {
+/// Handles `#line N`, which makes the line after the directive report as line `N`.
+/// Generated code uses it to point errors back at the original source.
+fn line_directive(source: &str, pointer: &mut ScannerPointer, line: &mut u32) -> Result<(), ErrorToken> {
+    let start = pointer.current;
+    while peek(source, pointer) != '\n' && !is_at_end(source, pointer) {
+        advance(source, pointer);
+    }
+
+    let directive = &source[start..pointer.current];
+    let number = match directive.strip_prefix("#line") {
+        Some(rest) if rest.starts_with([' ', '\t']) => rest.trim(),
+        _ => return Err(make_error_token("Malformed line directive. Expect '#line N'.", line)),
+    };
+
+    if number.is_empty() || !number.chars().all(is_digit) {
+        return Err(make_error_token("Malformed line directive. Expect '#line N'.", line));
+    }
+
+    match number.parse::<u32>() {
+        // The newline ending the directive bumps the counter up to `n`.
+        Ok(n) if n > 0 => {
+            *line = n - 1;
+            Ok(())
+        },
+        _ => Err(make_error_token("Line number in directive must be between 1 and 4294967295.", line)),
+    }
+}
+
+fn is_at_line_start(source: &str, pointer: &ScannerPointer) -> bool {
+    let before = &source[..pointer.current];
+    before.is_empty() || before.strip_prefix(BOM) == Some("") || before.ends_with('\n')
+}
+
+fn identifier(source: &str, pointer: &mut ScannerPointer, line: &mut u32) -> Token {
     while is_alphanumeric(peek(source, pointer)) {
         advance(source, pointer);
     }
@@ -140,19 +311,30 @@ fn identifier(source: &String, pointer: &mut ScannerPointer, line: &mut u32) ->
     let text = &source[pointer.start..pointer.current];
     let token_type = match text {
         "and" => TokenType::And,
+        "break" => TokenType::Break,
+        "catch" => TokenType::Catch,
         "class" => TokenType::Class,
+        "continue" => TokenType::Continue,
+        "del" => TokenType::Del,
+        "do" => TokenType::Do,
         "else" => TokenType::Else,
+        "eprint" => TokenType::EPrint,
         "false" => TokenType::False,
         "for" => TokenType::For,
         "fun" => TokenType::Fun,
         "if" => TokenType::If,
+        "import" => TokenType::Import,
+        "in" => TokenType::In,
         "nil" => TokenType::Nil,
         "or" => TokenType::Or,
         "print" => TokenType::Print,
         "return" => TokenType::Return,
         "super" => TokenType::Super,
         "this" => TokenType::This,
+        "throw" => TokenType::Throw,
         "true" => TokenType::True,
+        "try" => TokenType::Try,
+        "typeof" => TokenType::TypeOf,
         "var" => TokenType::Var,
         "while" => TokenType::While,
         _ => TokenType::Identifier,
@@ -161,7 +343,8 @@ fn identifier(source: &String, pointer: &mut ScannerPointer, line: &mut u32) ->
     make_token(token_type, pointer, line)
 }
 
-fn number(source: &String, pointer: &mut ScannerPointer, line: &mut u32) -> Token {
+/// Scans a number. A trailing `.` isn't part of it, so `1.` is the number `1` followed by a `Dot`.
+fn number(source: &str, pointer: &mut ScannerPointer, line: &mut u32) -> Token {
     while is_digit(peek(source, pointer)) {
         advance(source, pointer);
     }
@@ -172,19 +355,62 @@ fn number(source: &String, pointer: &mut ScannerPointer, line: &mut u32) -> Toke
             advance(source, pointer);
         }
     }
+    exponent(source, pointer);
 
     make_token(TokenType::Number, pointer, line)
 }
 
-fn string(source: &String, pointer: &mut ScannerPointer, line: &mut u32) -> Result<Token, ErrorToken> {
+/// Scans the digits of a number written with a leading dot, like `.5`.
+fn fraction(source: &str, pointer: &mut ScannerPointer, line: &mut u32) -> Token {
+    while is_digit(peek(source, pointer)) {
+        advance(source, pointer);
+    }
+    exponent(source, pointer);
+
+    make_token(TokenType::Number, pointer, line)
+}
+
+/// Consumes an exponent like `e10` or `E-3` after a number's digits. Without a digit after
+/// the `e` and optional sign it's left alone, so `1e` is the number `1` and the identifier `e`.
+fn exponent(source: &str, pointer: &mut ScannerPointer) {
+    let mut rest = source[pointer.current..].chars();
+    if !matches!(rest.next(), Some('e' | 'E')) {
+        return;
+    }
+
+    let sign = matches!(peek_next(source, pointer), '+' | '-');
+    let first_digit = if sign { rest.nth(1) } else { rest.next() };
+    if !first_digit.is_some_and(is_digit) {
+        return;
+    }
+
+    advance(source, pointer);
+    if sign {
+        advance(source, pointer);
+    }
+    while is_digit(peek(source, pointer)) {
+        advance(source, pointer);
+    }
+}
+
+/// An unterminated string only swallows the rest of its first line. Scanning picks up again on
+/// the next line, so mistakes further down still get reported.
+fn string(source: &str, pointer: &mut ScannerPointer, line: &mut u32) -> Result<Token, ErrorToken> {
+    let start_line = *line;
+    let mut first_newline = None;
     while peek(source, pointer) != '"' && !is_at_end(source, pointer) {
         if peek(source, pointer) == '\n' {
+            first_newline.get_or_insert(pointer.current);
             *line += 1;
         }
         advance(source, pointer);
     }
 
     if is_at_end(source, pointer) {
+        if let Some(newline) = first_newline {
+            pointer.current = newline;
+            *line = start_line;
+        }
         return Err(make_error_token("Unterminated string.", line));
     }
 
@@ -193,19 +419,39 @@ fn string(source: &String, pointer: &mut ScannerPointer, line: &mut u32) -> Resu
     Ok(make_token(TokenType::String, pointer, line))
 }
 
-fn match_char(source: &String, pointer: &mut ScannerPointer, expected: char) -> bool {
+/// Scans a `"""..."""` literal. Newlines and lone quotes inside are kept as is.
+fn triple_quoted_string(source: &str, pointer: &mut ScannerPointer, line: &mut u32) -> Result<Token, ErrorToken> {
+    // Skip the rest of the opening quotes.
+    advance(source, pointer);
+    advance(source, pointer);
+
+    while !source[pointer.current..].starts_with("\"\"\"") {
+        if is_at_end(source, pointer) {
+            return Err(make_error_token("Unterminated triple-quoted string.", line));
+        }
+        if advance(source, pointer) == '\n' {
+            *line += 1;
+        }
+    }
+
+    pointer.current += 3;
+
+    Ok(make_token(TokenType::String, pointer, line))
+}
+
+fn match_char(source: &str, pointer: &mut ScannerPointer, expected: char) -> bool {
     if is_at_end(source, pointer) {
         return false;
     }
-    if source.chars().nth(pointer.current).unwrap() != expected {
+    if peek(source, pointer) != expected {
         return false;
     }
 
-    pointer.current += 1;
+    pointer.current += expected.len_utf8();
     true
 }
 
-fn skip_whitespace(source: &String, pointer: &mut ScannerPointer, line: &mut u32) {
+fn skip_whitespace(source: &str, pointer: &mut ScannerPointer, line: &mut u32) {
     loop {
         let c = peek(source, pointer);
 
@@ -217,13 +463,9 @@ fn skip_whitespace(source: &String, pointer: &mut ScannerPointer, line: &mut u32
                 *line += 1;
                 advance(source, pointer);
             },
-            '/' => {
-                if peek_next(source, pointer) == '/' {
-                    while peek(source, pointer) != '\n' && !is_at_end(source, pointer) {
-                        advance(source, pointer);
-                    }
-                } else {
-                    return;
+            '/' if peek_next(source, pointer) == '/' && !pointer.preserve_comments => {
+                while peek(source, pointer) != '\n' && !is_at_end(source, pointer) {
+                    advance(source, pointer);
                 }
             },
             _ => return,
@@ -231,38 +473,33 @@ fn skip_whitespace(source: &String, pointer: &mut ScannerPointer, line: &mut u32
     }
 }
 
-fn advance (source: &String, pointer: &mut ScannerPointer) -> char {
-    pointer.current += 1;
-    source.chars().nth(pointer.current - 1).unwrap()
+fn advance (source: &str, pointer: &mut ScannerPointer) -> char {
+    let c = peek(source, pointer);
+    pointer.current += c.len_utf8();
+    c
 }
 
-fn peek(source: &String, pointer: &ScannerPointer) -> char {
-    if is_at_end(source, pointer) {
-        return '\0';
-    }
-    source.chars().nth(pointer.current).unwrap()
+fn peek(source: &str, pointer: &ScannerPointer) -> char {
+    source[pointer.current..].chars().next().unwrap_or('\0')
 }
 
-fn peek_next(source: &String, pointer: &ScannerPointer) -> char {
-    if pointer.current + 1 >= source.len() {
-        return '\0';
-    }
-    source.chars().nth(pointer.current + 1).unwrap()
+fn peek_next(source: &str, pointer: &ScannerPointer) -> char {
+    source[pointer.current..].chars().nth(1).unwrap_or('\0')
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    c >= 'a' && c <= 'z' || c >= 'A' && c <= 'Z' || c == '_'
+    c.is_ascii_alphabetic() || c == '_'
 }
 
 fn is_alphanumeric(c: char) -> bool {
     is_alpha(c) || is_digit(c)
 }
 
-fn is_at_end(source: &String, pointer: &ScannerPointer) -> bool {
+fn is_at_end(source: &str, pointer: &ScannerPointer) -> bool {
     pointer.current >= source.len()
 }
 
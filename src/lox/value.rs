@@ -1,4 +1,4 @@
-use crate::lox::object::StringId;
+use crate::lox::object::{StringId, Function, Native, ObjectId};
 
 use core::fmt;
 
@@ -8,14 +8,19 @@ pub enum Value {
     Bool(bool),
     Nil,
     String(StringId),
+    Function(Function),
+    Native(Native),
+    Closure(ObjectId),
+    Class(ObjectId),
+    Instance(ObjectId),
+    BoundMethod(ObjectId),
+    List(ObjectId),
+    Map(ObjectId),
 }
 
 impl Value {
     pub fn is_number(&self) -> bool {
-        match self {
-            Value::Number(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Number(_))
     }
 
     pub fn as_number(&self) -> f64 {
@@ -26,17 +31,11 @@ impl Value {
     }
 
     pub fn is_nil(&self) -> bool {
-        match self {
-            Value::Nil => true,
-            _ => false,
-        }
+        matches!(self, Value::Nil)
     }
 
     pub fn is_bool(&self) -> bool {
-        match self {
-            Value::Bool(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Bool(_))
     }
 
     pub fn as_bool(&self) -> bool {
@@ -47,9 +46,36 @@ impl Value {
     }
 
     pub fn is_string(&self) -> bool {
+        matches!(self, Value::String(_))
+    }
+
+    pub fn is_function(&self) -> bool {
+        matches!(self, Value::Function(_))
+    }
+
+    pub fn is_closure(&self) -> bool {
+        matches!(self, Value::Closure(_))
+    }
+
+    pub fn is_class(&self) -> bool {
+        matches!(self, Value::Class(_))
+    }
+
+    pub fn is_instance(&self) -> bool {
+        matches!(self, Value::Instance(_))
+    }
+
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Value::String(_) => true,
-            _ => false,
+            Value::Number(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Native(_) | Value::Closure(_) | Value::BoundMethod(_) => "function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
         }
     }
 }
@@ -57,14 +83,44 @@ impl Value {
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Value::Number(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", format_number(*value, None)),
             Value::Bool(value) => write!(f, "{}", value),
             Value::Nil => write!(f, "nil"),
             Value::String(value) => write!(f, "{}", value),
+            Value::Function(value) => write!(f, "{}", value),
+            Value::Native(value) => write!(f, "{}", value),
+            Value::Closure(value) => write!(f, "{}", value),
+            Value::Class(value) => write!(f, "{}", value),
+            Value::Instance(value) => write!(f, "{}", value),
+            Value::BoundMethod(value) => write!(f, "{}", value),
+            Value::List(value) => write!(f, "{}", value),
+            Value::Map(value) => write!(f, "{}", value),
         }
     }
 }
 
+/// Formats a number the way `print` shows it. Infinities print as `inf` and `-inf`,
+/// NaN as `nan`, and negative zero keeps its sign as `-0`. `precision` is the number
+/// of significant digits, or `None` for the shortest exact form.
+pub fn format_number(n: f64, precision: Option<usize>) -> String {
+    if n.is_nan() {
+        return String::from("nan");
+    }
+    if n.is_infinite() {
+        return String::from(if n > 0.0 { "inf" } else { "-inf" });
+    }
+
+    match precision {
+        // Round through scientific notation, then print the rounded value naturally.
+        Some(digits) => {
+            let rounded = format!("{:.*e}", digits.max(1) - 1, n);
+            rounded.parse::<f64>().unwrap().to_string()
+        },
+        None => n.to_string(),
+    }
+}
+
+#[derive(Clone)]
 pub struct ValueArray {
     pub values: Vec<Value>,
 }
@@ -72,7 +128,7 @@ pub struct ValueArray {
 impl ValueArray {
     pub fn new() -> ValueArray {
         ValueArray {
-            values: Vec::new(),
+            values: Vec::with_capacity(u8::MAX as usize),
         }
     }
 
@@ -84,3 +140,9 @@ impl ValueArray {
         &self.values[offset]
     }
 }
+
+impl Default for ValueArray {
+    fn default() -> Self {
+        Self::new()
+    }
+}
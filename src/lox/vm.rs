@@ -1,14 +1,43 @@
-use crate::lox::chunk::{Chunk, OpCode};
-use crate::lox::compiler::compile;
-use crate::lox::value::Value;
-use crate::lox::object::{StringId, DynamicStringStorage};
+use crate::lox::chunk::{operand_count, Chunk, OpCode};
+use crate::lox::compiler::{compile_file_into, compile_into, CompileError, CompileOptions};
+use crate::lox::value::{Value, format_number};
+use crate::lox::object::{StringId, DynamicStringStorage, Function, Native, ObjectId, ObjectStorage, Closure, Upvalue, Class, Instance, BoundMethod, List, Map, ValueKey, VARIADIC};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::io::{self, BufRead, Write};
+
+const FRAMES_MAX: usize = 64;
+/// Each frame can address up to 256 slots, so a full call stack never needs more than this.
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Signature of natives. Gets the arguments (already arity-checked unless the native is `VARIADIC`)
+/// and returns the call's result, or an error message that becomes a runtime error.
+pub type NativeFn = fn(args: &[Value], chunk: &Chunk, env: &mut Env) -> Result<Value, String>;
 
 pub enum InterpretResult {
     Ok,
     CompileError,
-    RuntimeError,
+    RuntimeError(RuntimeError),
+    /// The run used up its instruction budget before finishing.
+    Interrupted,
+}
+
+/// Why `VM::run_files` stopped.
+pub enum RunFilesError {
+    /// The file couldn't be read.
+    Read { path: String, message: String },
+    /// The file didn't compile, failed at runtime or was interrupted.
+    Interpret { path: String, result: InterpretResult },
+}
+
+pub struct RuntimeError {
+    pub message: String,
+    pub line: u32,
+    /// One entry per active call, innermost first, like `[line 3] in fib()`.
+    pub trace: Vec<String>,
 }
 
 struct Stack {
@@ -18,18 +47,34 @@ struct Stack {
 impl Stack {
     fn new() -> Stack {
         Stack {
-            values: Vec::new(),
+            values: Vec::with_capacity(STACK_MAX),
         }
     }
 
-    fn push(&mut self, value: &Value) {
-        self.values.push(value.clone())
+    fn push(&mut self, value: Value) {
+        self.values.push(value)
     }
 
     fn pop(&mut self) -> Value {
         self.values.pop().unwrap()
     }
 
+    fn get(&self, index: usize) -> &Value {
+        &self.values[index]
+    }
+
+    fn set(&mut self, index: usize, value: Value) {
+        self.values[index] = value;
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.values.truncate(len);
+    }
+
     fn reset(&mut self) {
         self.values.clear();
     }
@@ -38,34 +83,26 @@ impl Stack {
         &self.values[self.values.len() - 1 - distance]
     }
 
-    fn is_empty(&self) -> bool {
-        self.values.len() == 0
+    /// Overwrites the top value, for ops that map one value to another without a pop and push.
+    fn replace_top(&mut self, value: Value) {
+        let top = self.values.len() - 1;
+        self.values[top] = value;
     }
 
-    fn trace(&self) {
-        print!("           ");
-        if self.values.len() == 0 {
-            println!("<empty>");
+    fn trace(&self, out: &mut String) {
+        out.push_str("           ");
+        if self.values.is_empty() {
+            out.push_str("<empty>\n");
             return;
         }
 
-        for i in 0..self.values.len() {
-            print!("[ ");
-            print!("{}", self.values[i]);
-            print!(" ]");
+        for value in self.values.iter() {
+            out.push_str(&format!("[ {} ]", value));
         }
-        println!("");
+        out.push('\n');
     }
 }
 
-macro_rules! dbg {
-    ($($arg:tt)*) => {
-        if cfg!(debug_assertions) {
-            println!($($arg)*);
-        }
-    };
-}
-
 macro_rules! dbg_if {
     ($cond: expr, $($arg:tt)*) => {
         if cfg!(debug_assertions) && $cond {
@@ -85,74 +122,688 @@ macro_rules! dbg_var {
     }
 }
 
-macro_rules! stack_trace {
-    ($env: ident) => {
-        if cfg!(debug_assertions) {
-            $env.stack.trace();
-        }
-    }
-}
-
-macro_rules! disassemble_instruction {
-    ($chunk: ident, $ip: ident) => {
-        if cfg!(debug_assertions) {
-            $chunk.disassemble_instruction($ip);
+macro_rules! binary {
+    ($env: ident, $chunk: ident, $opcode: ident, $op: tt, $res_type: expr, $ip: tt, $debug: expr) => {
+        {
+            if !$env.stack.peek(0).is_number() || !$env.stack.peek(1).is_number() {
+                let msg = format!(
+                    "Operands must be numbers, got {} and {}.",
+                    $env.stack.peek(1).type_name(),
+                    $env.stack.peek(0).type_name(),
+                );
+                return runtime_error($env, $chunk, $opcode, $ip, &msg);
+            }
+            let b = $env.stack.pop();
+            let a = $env.stack.pop();
+            $env.stack.push($res_type(a.as_number() $op b.as_number()));
+            dbg_if!($debug, "{} {} {}", stringify!($op), a, b);
+            $ip += 1;
         }
     }
 }
 
-macro_rules! binary {
-    ($env: ident, $op: tt, $res_type: expr, $ip: tt, $debug: expr) => {
+/// Like `binary!` for `<` and `>`, but also compares two strings lexicographically.
+macro_rules! compare {
+    ($env: ident, $chunk: ident, $opcode: ident, $op: tt, $ip: tt, $debug: expr) => {
         {
             let b = $env.stack.pop();
             let a = $env.stack.pop();
-            $env.stack.push(&$res_type(a.as_number() $op b.as_number()));
+            let result = match (&a, &b) {
+                (Value::Number(a), Value::Number(b)) => a $op b,
+                (Value::String(a), Value::String(b)) => {
+                    let a_str = resolve_string(a, $chunk, $env);
+                    let b_str = resolve_string(b, $chunk, $env);
+                    a_str $op b_str
+                },
+                (a, b) => {
+                    let msg = format!(
+                        "Operands must be two numbers or two strings, got {} and {}.",
+                        a.type_name(),
+                        b.type_name(),
+                    );
+                    return runtime_error($env, $chunk, $opcode, $ip, &msg);
+                }
+            };
             dbg_if!($debug, "{} {} {}", stringify!($op), a, b);
+            $env.stack.push(Value::Bool(result));
             $ip += 1;
         }
     }
 }
 
-struct Env {
+struct CallFrame {
+    closure: ObjectId,
+    // Where to resume this frame once the function it called returns.
+    ip: usize,
+    slot: usize,
+}
+
+/// An active `try` block: where its `catch` starts, and the frames and stack to unwind to.
+struct Handler {
+    frames: usize,
+    stack_len: usize,
+    catch_ip: usize,
+}
+
+pub struct Env {
     stack: Stack,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
     globals: HashMap<StringId, Value>,
+    /// Globals the host defined, natives included. `reset` restores them and `run_chunk`
+    /// carries them over to the new chunk.
+    host_globals: HashMap<StringId, Value>,
     dynamic_strings: DynamicStringStorage,
+    closures: ObjectStorage<Closure>,
+    upvalues: ObjectStorage<Upvalue>,
+    classes: ObjectStorage<Class>,
+    instances: ObjectStorage<Instance>,
+    bound_methods: ObjectStorage<BoundMethod>,
+    lists: ObjectStorage<List>,
+    maps: ObjectStorage<Map>,
+    // Upvalues still pointing into the stack, so closures capturing the same slot share one.
+    open_upvalues: Vec<ObjectId>,
+    next_gc: usize,
+    number_precision: Option<usize>,
+    print_json: bool,
+    /// Where `readLine` reads from. `None` reads from stdin.
+    input: Option<Box<dyn BufRead>>,
+    /// Where `print`, `log` and tracing write. Stdout by default.
+    output: Box<dyn Write>,
+    /// Where compile and runtime errors are reported. Stderr by default.
+    errors: Box<dyn Write>,
 }
 
 impl Env {
     fn new() -> Env {
         Env {
             stack: Stack::new(),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            handlers: Vec::new(),
             globals: HashMap::new(),
+            host_globals: HashMap::new(),
             dynamic_strings: DynamicStringStorage::new(),
+            closures: ObjectStorage::new(),
+            upvalues: ObjectStorage::new(),
+            classes: ObjectStorage::new(),
+            instances: ObjectStorage::new(),
+            bound_methods: ObjectStorage::new(),
+            lists: ObjectStorage::new(),
+            maps: ObjectStorage::new(),
+            open_upvalues: Vec::new(),
+            next_gc: GC_INITIAL_THRESHOLD,
+            number_precision: None,
+            print_json: false,
+            input: None,
+            output: Box::new(io::stdout()),
+            errors: Box::new(io::stderr()),
+        }
+    }
+
+    /// Drops everything belonging to the current run, keeping globals and the heap.
+    fn reset_execution(&mut self) {
+        self.stack.reset();
+        self.frames.clear();
+        self.handlers.clear();
+        self.open_upvalues.clear();
+    }
+
+    /// Drops everything scripts created, keeping the host's globals and the allocated storage.
+    fn reset(&mut self) {
+        self.reset_execution();
+        self.globals = self.host_globals.clone();
+        self.dynamic_strings.clear();
+        self.closures.clear();
+        self.upvalues.clear();
+        self.classes.clear();
+        self.instances.clear();
+        self.bound_methods.clear();
+        self.lists.clear();
+        self.maps.clear();
+        self.next_gc = GC_INITIAL_THRESHOLD;
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No call frame")
+    }
+
+    fn frame_upvalue(&self, slot: usize) -> ObjectId {
+        self.closures.get(&self.frame().closure).upvalues[slot]
+    }
+
+    /// Pushes a frame for `closure`, whose arguments are already on the stack,
+    /// and returns the ip to continue at.
+    fn call_closure(&mut self, closure: ObjectId, arg_count: usize, return_ip: usize) -> Result<usize, String> {
+        let function = &self.closures.get(&closure).function;
+        if arg_count != function.arity as usize {
+            return Err(format!("Expected {} arguments but got {}.", function.arity, arg_count));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(String::from("Stack overflow."));
+        }
+
+        let ip = function.entry;
+        self.frames.last_mut().expect("No call frame").ip = return_ip;
+        let slot = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame { closure, ip, slot });
+
+        Ok(ip)
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize, return_ip: usize, chunk: &Chunk) -> Result<usize, String> {
+        let slot = self.stack.len() - arg_count - 1;
+
+        match callee {
+            Value::Closure(closure) => self.call_closure(closure, arg_count, return_ip),
+            Value::Class(class) => {
+                let instance = self.instances.add(Instance { class, fields: HashMap::new() });
+                self.stack.set(slot, Value::Instance(instance));
+
+                match self.classes.get(&class).initializer {
+                    Some(initializer) => self.call_closure(initializer, arg_count, return_ip),
+                    None if arg_count != 0 => Err(format!("Expected 0 arguments but got {}.", arg_count)),
+                    None => Ok(return_ip),
+                }
+            },
+            Value::BoundMethod(bound) => {
+                let bound = self.bound_methods.get(&bound);
+                let method = bound.method;
+                let receiver = bound.receiver.clone();
+                self.stack.set(slot, receiver);
+                self.call_closure(method, arg_count, return_ip)
+            },
+            Value::Native(native) => {
+                if native.arity != VARIADIC && arg_count != native.arity as usize {
+                    return Err(format!("Expected {} arguments but got {}.", native.arity, arg_count));
+                }
+
+                let args: Vec<Value> = (slot + 1..self.stack.len()).map(|i| self.stack.get(i).clone()).collect();
+                let result = (native.function)(&args, chunk, self)?;
+                self.stack.truncate(slot);
+                self.stack.push(result);
+                Ok(return_ip)
+            },
+            _ => Err(String::from("Can only call functions and classes.")),
+        }
+    }
+
+    fn invoke(&mut self, name: &StringId, arg_count: usize, return_ip: usize, chunk: &Chunk) -> Result<usize, String> {
+        let instance = match self.stack.peek(arg_count) {
+            Value::Instance(instance) => *instance,
+            other => return Err(format!("Only instances have methods, got {}.", other.type_name())),
+        };
+
+        // A field holding a function shadows a method of the same name.
+        if let Some(value) = self.instances.get(&instance).fields.get(name).cloned() {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack.set(slot, value.clone());
+            return self.call_value(value, arg_count, return_ip, chunk);
+        }
+
+        let class = self.instances.get(&instance).class;
+        self.invoke_from_class(class, name, arg_count, return_ip, chunk)
+    }
+
+    fn invoke_from_class(&mut self, class: ObjectId, name: &StringId, arg_count: usize, return_ip: usize, chunk: &Chunk) -> Result<usize, String> {
+        match self.classes.get(&class).methods.get(name) {
+            Some(method) => self.call_closure(*method, arg_count, return_ip),
+            None => Err(format!("Undefined property '{}'.", chunk.read_string_literal(name))),
+        }
+    }
+
+    fn bind_method(&mut self, receiver: Value, class: ObjectId, name: &StringId) -> Option<Value> {
+        let method = *self.classes.get(&class).methods.get(name)?;
+        let bound = self.bound_methods.add(BoundMethod { receiver, method });
+
+        Some(Value::BoundMethod(bound))
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjectId {
+        for id in self.open_upvalues.iter() {
+            if let Upvalue::Open(open_slot) = self.upvalues.get(id) {
+                if *open_slot == slot {
+                    return *id;
+                }
+            }
+        }
+
+        let id = self.upvalues.add(Upvalue::Open(slot));
+        self.open_upvalues.push(id);
+        id
+    }
+
+    fn heap_size(&self) -> usize {
+        self.dynamic_strings.size()
+            + self.closures.size()
+            + self.upvalues.size()
+            + self.classes.size()
+            + self.instances.size()
+            + self.bound_methods.size()
+            + self.lists.size()
+            + self.maps.size()
+    }
+
+    /// Marks everything reachable from the stack, globals, call frames, and open upvalues,
+    /// then frees the rest. Only runs between instructions, so nothing in flight is lost.
+    fn collect_garbage(&mut self) {
+        let mut strings = HashSet::new();
+        let mut closures = HashSet::new();
+        let mut marked_upvalues = HashSet::new();
+        let mut classes = HashSet::new();
+        let mut instances = HashSet::new();
+        let mut bound_methods = HashSet::new();
+        let mut lists = HashSet::new();
+        let mut maps = HashSet::new();
+
+        let mut gray: Vec<Value> = Vec::new();
+        gray.extend(self.stack.values.iter().cloned());
+        gray.extend(self.globals.values().cloned());
+        gray.extend(self.frames.iter().map(|frame| Value::Closure(frame.closure)));
+
+        let mut upvalues: Vec<ObjectId> = self.open_upvalues.clone();
+
+        loop {
+            if let Some(value) = gray.pop() {
+                match value {
+                    Value::String(id) if !id.is_literal() => {
+                        strings.insert(id);
+                    },
+                    Value::Closure(id) => {
+                        if !closures.insert(id) {
+                            continue;
+                        }
+                        upvalues.extend(self.closures.get(&id).upvalues.iter().cloned());
+                    },
+                    Value::Class(id) => {
+                        if !classes.insert(id) {
+                            continue;
+                        }
+                        let class = self.classes.get(&id);
+                        gray.extend(class.methods.values().map(|method| Value::Closure(*method)));
+                    },
+                    Value::Instance(id) => {
+                        if !instances.insert(id) {
+                            continue;
+                        }
+                        let instance = self.instances.get(&id);
+                        gray.push(Value::Class(instance.class));
+                        gray.extend(instance.fields.values().cloned());
+                    },
+                    Value::BoundMethod(id) => {
+                        if !bound_methods.insert(id) {
+                            continue;
+                        }
+                        let bound = self.bound_methods.get(&id);
+                        gray.push(bound.receiver.clone());
+                        gray.push(Value::Closure(bound.method));
+                    },
+                    Value::List(id) => {
+                        if !lists.insert(id) {
+                            continue;
+                        }
+                        gray.extend(self.lists.get(&id).items.iter().cloned());
+                    },
+                    Value::Map(id) => {
+                        if !maps.insert(id) {
+                            continue;
+                        }
+                        gray.extend(self.maps.get(&id).entries.values().cloned());
+                    },
+                    _ => (),
+                }
+            } else if let Some(id) = upvalues.pop() {
+                if marked_upvalues.insert(id) {
+                    if let Upvalue::Closed(value) = self.upvalues.get(&id) {
+                        gray.push(value.clone());
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+
+        self.dynamic_strings.sweep(&strings);
+        self.closures.sweep(&closures);
+        self.upvalues.sweep(&marked_upvalues);
+        self.classes.sweep(&classes);
+        self.instances.sweep(&instances);
+        self.bound_methods.sweep(&bound_methods);
+        self.lists.sweep(&lists);
+        self.maps.sweep(&maps);
+
+        self.next_gc = usize::max(self.heap_size() * GC_HEAP_GROW_FACTOR, GC_INITIAL_THRESHOLD);
+    }
+
+    /// Moves every open upvalue at or above `last` off the stack and into the upvalue itself.
+    fn close_upvalues(&mut self, last: usize) {
+        let mut i = 0;
+        while i < self.open_upvalues.len() {
+            let id = self.open_upvalues[i];
+            let slot = match self.upvalues.get(&id) {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => panic!("Closed upvalue in open list"),
+            };
+
+            if slot >= last {
+                *self.upvalues.get_mut(&id) = Upvalue::Closed(self.stack.get(slot).clone());
+                self.open_upvalues.swap_remove(i);
+            } else {
+                i += 1;
+            }
         }
     }
 }
 
-pub fn interpret(source: &String, debug: bool) -> InterpretResult {
-    let mut env = Env::new();
-    let res = compile(&source);
-    match res {
-        Ok(chunk) => {
-            run(&chunk, &mut env, debug)
+/// What the VM prints while running. Everything is off by default and works in any build profile.
+#[derive(Clone, Copy, Default)]
+pub struct TraceOptions {
+    /// Disassemble the chunk before running it.
+    pub disassemble: bool,
+    /// Print the stack and the next instruction before executing it.
+    pub instructions: bool,
+}
+
+pub struct VM {
+    env: Env,
+    /// Every interpreted source is appended here, so global names and functions
+    /// from earlier runs stay valid.
+    chunk: Chunk,
+    debug: bool,
+    pub trace: TraceOptions,
+    pub compile_options: CompileOptions,
+    /// Instructions left to execute before the run is interrupted. `None` means no limit.
+    pub instruction_budget: Option<u64>,
+    /// Run `Chunk::verify` before executing. On by default.
+    pub verify_chunks: bool,
+    /// Significant digits `print` uses for numbers. `None` keeps Rust's shortest formatting.
+    pub number_precision: Option<usize>,
+    /// Make `print` and `log` write values as JSON instead of the human-readable form.
+    pub print_json: bool,
+}
+
+impl VM {
+    pub fn new(debug: bool) -> VM {
+        let mut vm = VM {
+            env: Env::new(),
+            chunk: Chunk::new(),
+            debug,
+            trace: TraceOptions::default(),
+            compile_options: CompileOptions::default(),
+            instruction_budget: None,
+            verify_chunks: true,
+            number_precision: None,
+            print_json: false,
+        };
+
+        vm.define_native("log", 1, native_log).expect("Failed to define native");
+        vm.define_native("len", 1, native_len).expect("Failed to define native");
+        vm.define_native("substr", 3, native_substr).expect("Failed to define native");
+        vm.define_native("clone", 1, native_clone).expect("Failed to define native");
+        vm.define_native("floor", 1, native_floor).expect("Failed to define native");
+        vm.define_native("ceil", 1, native_ceil).expect("Failed to define native");
+        vm.define_native("round", 1, native_round).expect("Failed to define native");
+        vm.define_native("abs", 1, native_abs).expect("Failed to define native");
+        vm.define_native("sqrt", 1, native_sqrt).expect("Failed to define native");
+        vm.define_native("min", VARIADIC, native_min).expect("Failed to define native");
+        vm.define_native("max", VARIADIC, native_max).expect("Failed to define native");
+        vm.define_native("parseNumber", 1, native_parse_number).expect("Failed to define native");
+        vm.define_native("readLine", 0, native_read_line).expect("Failed to define native");
+        vm.define_native("fmt", VARIADIC, native_fmt).expect("Failed to define native");
+
+        vm
+    }
+
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        let res = compile_into(source, &mut self.chunk, self.compile_options);
+        self.run_compiled(res)
+    }
+
+    /// Like `interpret`, for `source` read from the file at `path`, so the file importing
+    /// itself is reported as an import cycle.
+    pub fn interpret_file(&mut self, source: &str, path: &str) -> InterpretResult {
+        let res = compile_file_into(source, path, &mut self.chunk, self.compile_options);
+        self.run_compiled(res)
+    }
+
+    fn run_compiled(&mut self, res: Result<usize, Vec<CompileError>>) -> InterpretResult {
+        match res {
+            Ok(entry) => {
+                if self.verify_chunks {
+                    if let Err(msg) = self.chunk.verify() {
+                        writeln!(self.env.errors, "Invalid chunk: {}", msg).expect("Failed to write error");
+                        return InterpretResult::CompileError;
+                    }
+                }
+
+                if self.compile_options.strict_globals {
+                    let errors = self.undefined_globals(entry);
+                    if !errors.is_empty() {
+                        for error in errors {
+                            writeln!(self.env.errors, "{}", error).expect("Failed to write error");
+                        }
+                        return InterpretResult::CompileError;
+                    }
+                }
+
+                self.run_script(entry)
+            }
+            Err(errors) => {
+                for error in errors {
+                    writeln!(self.env.errors, "{}", error).expect("Failed to write error");
+                }
+                InterpretResult::CompileError
+            },
+        }
+    }
+
+    /// Replaces the VM's code with `chunk` and runs it from its first byte as the top-level script,
+    /// like code built with `ChunkBuilder`. Natives and other globals the host defined are kept;
+    /// globals defined by the replaced code go away with it.
+    pub fn run_chunk(&mut self, mut chunk: Chunk) -> InterpretResult {
+        // Globals are keyed by the old chunk's literal ids, so their names move to the new chunk.
+        let host_globals = std::mem::take(&mut self.env.host_globals);
+        for (id, value) in host_globals {
+            let name = self.chunk.read_string_literal(&id);
+            match chunk.add_or_retrieve_string_literal(name) {
+                Ok(id) => {
+                    self.env.host_globals.insert(id, value);
+                },
+                Err(msg) => {
+                    writeln!(self.env.errors, "Invalid chunk: {}", msg).expect("Failed to write error");
+                    return InterpretResult::CompileError;
+                },
+            }
+        }
+        self.env.globals = self.env.host_globals.clone();
+        self.chunk = chunk;
+        if self.verify_chunks {
+            if let Err(msg) = self.chunk.verify() {
+                writeln!(self.env.errors, "Invalid chunk: {}", msg).expect("Failed to write error");
+                return InterpretResult::CompileError;
+            }
+        }
+
+        self.run_script(0)
+    }
+
+    fn run_script(&mut self, entry: usize) -> InterpretResult {
+        if self.trace.disassemble {
+            let listing = self.chunk.disassemble_to_string("script");
+            write!(self.env.output, "{}", listing).expect("Failed to write trace");
         }
-        Err(_) => InterpretResult::CompileError,
+
+        let env = &mut self.env;
+        let script = Function {
+            name: None,
+            arity: 0,
+            entry,
+            upvalue_count: 0,
+        };
+        let closure = env.closures.add(Closure { function: script, upvalues: Vec::new() });
+        env.stack.push(Value::Closure(closure));
+        env.frames.push(CallFrame { closure, ip: entry, slot: 0 });
+
+        env.number_precision = self.number_precision;
+        env.print_json = self.print_json;
+        run(&self.chunk, env, self.debug, self.trace, &mut self.instruction_budget)
+    }
+
+    /// Reports globals the code from `entry` on uses without anything defining them.
+    fn undefined_globals(&self, entry: usize) -> Vec<CompileError> {
+        self.chunk.undefined_globals(entry)
+            .into_iter()
+            .filter(|(id, _)| !self.env.globals.contains_key(id))
+            .map(|(id, offset)| {
+                let name = self.chunk.read_string_literal(&id);
+                let span = self.chunk.get_span(offset);
+                CompileError {
+                    message: format!("Undefined variable '{}'.", name),
+                    line: self.chunk.line_at_instruction(offset),
+                    location: format!(" at '{}'", name),
+                    start: span.map_or(0, |span| span.start),
+                    length: span.map_or(0, |span| span.length),
+                }
+            })
+            .collect()
+    }
+
+    /// Runs each file in order with the same globals, as if they were one script split across files.
+    /// Stops at the first file that can't be read or doesn't run to completion.
+    pub fn run_files(&mut self, paths: &[&str]) -> Result<(), RunFilesError> {
+        for path in paths {
+            let source = read_to_string(path).map_err(|err| RunFilesError::Read {
+                path: path.to_string(),
+                message: err.to_string(),
+            })?;
+
+            match self.interpret_file(&source, path) {
+                InterpretResult::Ok => {},
+                result => return Err(RunFilesError::Interpret { path: path.to_string(), result }),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    /// Defines a global from the host before (or between) `interpret` calls.
+    /// Fails only when the chunk can't hold another string literal for the name.
+    pub fn define_global(&mut self, name: &str, value: Value) -> Result<(), String> {
+        let name = self.chunk.add_or_retrieve_string_literal(name)?;
+        self.env.globals.insert(name.clone(), value.clone());
+        self.env.host_globals.insert(name, value);
+
+        Ok(())
+    }
+
+    /// Reserves room for `additional` more globals, for hosts that define many up front.
+    pub fn reserve_globals(&mut self, additional: usize) {
+        self.env.globals.reserve(additional);
+    }
+
+    /// Makes a Rust function callable from scripts as the global `name`.
+    pub fn define_native(&mut self, name: &'static str, arity: u8, function: NativeFn) -> Result<(), String> {
+        self.define_global(name, Value::Native(Native { name, arity, function }))
+    }
+
+    /// Makes `readLine` read from `input` instead of stdin.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.env.input = Some(input);
+    }
+
+    /// Sends `print` and `log` output, and any trace, to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.env.output = output;
+    }
+
+    /// Reports compile and runtime errors to `errors` instead of stderr.
+    pub fn set_errors(&mut self, errors: Box<dyn Write>) {
+        self.env.errors = errors;
+    }
+
+    /// Returns the VM to a fresh state for running unrelated scripts one after another:
+    /// globals, runtime strings and objects are dropped, but natives and other globals the host
+    /// defined come back with their original values.
+    /// Compiled code and settings like `trace` are kept.
+    pub fn reset(&mut self) {
+        self.env.reset();
+    }
+
+    /// Lists every global as `name = value`, one per line, sorted by name.
+    pub fn dump_globals(&self) -> String {
+        let mut globals: Vec<(&str, String)> = self.env.globals.iter()
+            .map(|(name, value)| {
+                (self.chunk.read_string_literal(name), format_value(value, &self.chunk, &self.env, self.number_precision))
+            })
+            .collect();
+        globals.sort();
+
+        globals.iter()
+            .map(|(name, value)| format!("{} = {}\n", name, value))
+            .collect()
     }
 }
 
-fn run(chunk: &Chunk, env: &mut Env, debug: bool) -> InterpretResult {
-    chunk.print_codes();
+pub fn interpret(source: &str, debug: bool) -> InterpretResult {
+    VM::new(debug).interpret(source)
+}
+
+/// Runs until the script finishes. A runtime error inside a `try` block resumes at its `catch`
+/// with the message on the stack; an error outside of one ends the run.
+fn run(chunk: &Chunk, env: &mut Env, debug: bool, trace: TraceOptions, budget: &mut Option<u64>) -> InterpretResult {
+    loop {
+        let error = match execute(chunk, env, debug, trace, budget) {
+            InterpretResult::RuntimeError(error) => error,
+            result => return result,
+        };
+
+        let handler = match env.handlers.pop() {
+            Some(handler) => handler,
+            None => return InterpretResult::RuntimeError(error),
+        };
+        dbg_if!(debug, "Catch {} at {:04}", error.message, handler.catch_ip);
+
+        env.close_upvalues(handler.stack_len);
+        env.stack.truncate(handler.stack_len);
+        env.frames.truncate(handler.frames);
+        env.frames.last_mut().expect("No call frame").ip = handler.catch_ip;
+
+        let message = env.dynamic_strings.add_string(&error.message).expect("Too many dynamic strings");
+        env.stack.push(Value::String(message));
+    }
+}
 
-    let mut ip = 0;
+fn execute(chunk: &Chunk, env: &mut Env, debug: bool, trace: TraceOptions, budget: &mut Option<u64>) -> InterpretResult {
+    let mut ip = env.frames.last().unwrap().ip;
+    // The current frame's stack base, refreshed whenever the frame changes so
+    // local variable access doesn't go through `env.frames` every time.
+    let mut slot_base = env.frame().slot;
     loop {
-        if cfg!(debug_assertions) {
-            if debug {
-                dbg!("");
-                dbg!("Stack");
-                stack_trace!(env);
-                dbg!("Instruction");
-                disassemble_instruction!(chunk, ip);
+        if let Some(remaining) = budget {
+            if *remaining == 0 {
+                dbg_if!(debug, "Instruction budget exhausted");
+                env.reset_execution();
+                return InterpretResult::Interrupted;
             }
+            *remaining -= 1;
+        }
+
+        if env.heap_size() > env.next_gc {
+            let before = env.heap_size();
+            env.collect_garbage();
+            dbg_if!(debug, "GC collected {} bytes (from {} to {}) next at {}", before - env.heap_size(), before, env.heap_size(), env.next_gc);
+        }
+
+        if trace.instructions {
+            let mut out = String::from("\nStack\n");
+            env.stack.trace(&mut out);
+            out.push_str("Instruction\n");
+            chunk.disassemble_instruction(&mut out, ip);
+            write!(env.output, "{}", out).expect("Failed to write trace");
         }
 
         let instruction = chunk.byte(ip);
@@ -160,28 +811,40 @@ fn run(chunk: &Chunk, env: &mut Env, debug: bool) -> InterpretResult {
         match opcode {
             OpCode::Constant => {
                 let constant = chunk.read_constant(ip + 1);
-                env.stack.push(constant);
+                env.stack.push(constant.clone());
                 dbg_if!(debug, "Read {}", constant);
                 ip += 2;
             },
-            OpCode::StringLiteral => {
-                let string_idx = chunk.byte(ip + 1);
-                env.stack.push(&Value::String(StringId::new_literal_id(string_idx)));
-                dbg_if!(debug, "Push StringLiteral {}", string_idx);
+            OpCode::ConstantLong => {
+                let constant = chunk.read_constant_long(ip + 1);
+                env.stack.push(constant.clone());
+                dbg_if!(debug, "Read {}", constant);
+                ip += 4;
+            },
+            OpCode::Int => {
+                let value = chunk.byte(ip + 1);
+                env.stack.push(Value::Number(value as f64));
+                dbg_if!(debug, "Push Int {}", value);
                 ip += 2;
+            },
+            OpCode::StringLiteral | OpCode::StringLiteralLong => {
+                let id = chunk.read_literal_operand(ip);
+                dbg_if!(debug, "Push StringLiteral {}", id.0);
+                env.stack.push(Value::String(id));
+                ip += 1 + operand_count(opcode);
             }
             OpCode::Nil => {
-                env.stack.push(&Value::Nil);
+                env.stack.push(Value::Nil);
                 dbg_if!(debug, "Push Nil");
                 ip += 1;
             },
             OpCode::True => {
-                env.stack.push(&Value::Bool(true));
+                env.stack.push(Value::Bool(true));
                 dbg_if!(debug, "Push True");
                 ip += 1;
             },
             OpCode::False => {
-                env.stack.push(&Value::Bool(false));
+                env.stack.push(Value::Bool(false));
                 dbg_if!(debug, "Push False");
                 ip += 1;
             },
@@ -190,158 +853,1193 @@ fn run(chunk: &Chunk, env: &mut Env, debug: bool) -> InterpretResult {
                 dbg_if!(debug, "Pop");
                 ip += 1;
             },
-            OpCode::GetGlobal => {
-                let id = chunk.byte(ip + 1);
-                let id = StringId::new_literal_id(id);
+            OpCode::GetLocal => {
+                let slot = chunk.byte(ip + 1) as usize;
+                let value = env.stack.get(slot_base + slot).clone();
+                dbg_if!(debug, "Get Local {} {}", slot, value);
+                env.stack.push(value);
+                ip += 2;
+            },
+            OpCode::SetLocal => {
+                let slot = chunk.byte(ip + 1) as usize;
+                let value = env.stack.peek(0).clone();
+                dbg_if!(debug, "Set Local {} {}", slot, value);
+                let index = slot_base + slot;
+                env.stack.set(index, value);
+                ip += 2;
+            },
+            OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                let id = chunk.read_literal_operand(ip);
                 let value = env.globals.get(&id);
                 let value = match value {
                     Some(v) => v,
                     None => {
-                        let msg = format!("Undefined variable '{}'", chunk.read_string_literal(&id));
-                        runtime_error(&mut env.stack, opcode, chunk.get_line(ip), &msg);
-                        return InterpretResult::RuntimeError;
+                        let msg = format!("Undefined variable '{}'.", chunk.read_string_literal(&id));
+                        return runtime_error(env, chunk, opcode, ip, &msg);
                     }
                 };
 
                 env.stack.pop(); // Pop the id
-                env.stack.push(value);
                 dbg_if!(debug, "Get Global {}", value);
-                ip += 2;
+                env.stack.push(value.clone());
+                ip += 1 + operand_count(opcode);
+            },
+            OpCode::DeleteGlobal | OpCode::DeleteGlobalLong => {
+                let id = chunk.read_literal_operand(ip);
+                if env.globals.remove(&id).is_none() {
+                    let msg = format!("Undefined variable '{}'.", chunk.read_string_literal(&id));
+                    return runtime_error(env, chunk, opcode, ip, &msg);
+                }
+
+                env.stack.pop(); // Pop the id
+                dbg_if!(debug, "Delete Global {}", id);
+                ip += 1 + operand_count(opcode);
             },
-            OpCode::DefineGlobal => {
-                let id = chunk.byte(ip + 1);
-                let id = StringId::new_literal_id(id);
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                let id = chunk.read_literal_operand(ip);
                 let value = env.stack.peek(0);
-                dbg!("Define Global");
+                dbg_if!(debug, "Define Global");
                 dbg_var!(debug, chunk, id, value);
                 env.globals.insert(id, value.clone());
                 env.stack.pop(); // Pop the value
                 env.stack.pop(); // Pop the id
-                ip += 2;
+                ip += 1 + operand_count(opcode);
             },
-            OpCode::SetGlobal => {
-                let id = chunk.byte(ip + 1);
-                let id = StringId::new_literal_id(id);
-                let value = env.stack.peek(0);
-                dbg!("Set Global");
+            OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                let id = chunk.read_literal_operand(ip);
+                // Assigning never creates a global; only `var` does.
+                if !env.globals.contains_key(&id) {
+                    let msg = format!("Undefined variable '{}'.", chunk.read_string_literal(&id));
+                    return runtime_error(env, chunk, opcode, ip, &msg);
+                }
+
+                let value = env.stack.pop();
+                dbg_if!(debug, "Set Global");
                 dbg_var!(debug, chunk, id, value);
                 env.globals.insert(id, value.clone());
+                env.stack.pop(); // Pop the id
+                // Assignment is an expression, so the value stays on the stack.
+                env.stack.push(value);
+                ip += 1 + operand_count(opcode);
+            },
+            OpCode::GetUpvalue => {
+                let slot = chunk.byte(ip + 1) as usize;
+                let value = match env.upvalues.get(&env.frame_upvalue(slot)) {
+                    Upvalue::Open(index) => env.stack.get(*index).clone(),
+                    Upvalue::Closed(value) => value.clone(),
+                };
+                dbg_if!(debug, "Get Upvalue {} {}", slot, value);
+                env.stack.push(value);
+                ip += 2;
+            },
+            OpCode::SetUpvalue => {
+                let slot = chunk.byte(ip + 1) as usize;
+                let value = env.stack.peek(0).clone();
+                dbg_if!(debug, "Set Upvalue {} {}", slot, value);
+                let id = env.frame_upvalue(slot);
+                match env.upvalues.get_mut(&id) {
+                    Upvalue::Open(index) => {
+                        let index = *index;
+                        env.stack.set(index, value);
+                    },
+                    Upvalue::Closed(closed) => *closed = value,
+                }
+                ip += 2;
+            },
+            OpCode::GetProperty => {
+                let name = StringId::new_literal_id(chunk.byte(ip + 1));
+                let instance = match env.stack.peek(0) {
+                    Value::Instance(instance) => *instance,
+                    other => {
+                        let msg = format!("Only instances have properties, got {}.", other.type_name());
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
+                };
+
+                let value = match env.instances.get(&instance).fields.get(&name) {
+                    Some(value) => value.clone(),
+                    None => {
+                        let class = env.instances.get(&instance).class;
+                        match env.bind_method(Value::Instance(instance), class, &name) {
+                            Some(bound) => bound,
+                            None => {
+                                let msg = format!("Undefined property '{}'.", chunk.read_string_literal(&name));
+                                return runtime_error(env, chunk, opcode, ip, &msg);
+                            }
+                        }
+                    }
+                };
+
+                env.stack.pop(); // Pop the instance
+                dbg_if!(debug, "Get Property {} {}", chunk.read_string_literal(&name), value);
+                env.stack.push(value);
+                ip += 2;
+            },
+            OpCode::SetProperty => {
+                let name = StringId::new_literal_id(chunk.byte(ip + 1));
+                let instance = match env.stack.peek(1) {
+                    Value::Instance(instance) => *instance,
+                    other => {
+                        let msg = format!("Only instances have fields, got {}.", other.type_name());
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
+                };
+
+                let value = env.stack.pop();
+                dbg_if!(debug, "Set Property {} {}", chunk.read_string_literal(&name), value);
+                env.instances.get_mut(&instance).fields.insert(name, value.clone());
+                env.stack.pop(); // Pop the instance
+                env.stack.push(value);
+                ip += 2;
+            },
+            OpCode::GetSuper => {
+                let name = StringId::new_literal_id(chunk.byte(ip + 1));
+                let superclass = match env.stack.pop() {
+                    Value::Class(class) => class,
+                    _ => panic!("Superclass must be a class"),
+                };
+                let receiver = env.stack.pop();
+
+                match env.bind_method(receiver, superclass, &name) {
+                    Some(bound) => {
+                        dbg_if!(debug, "Get Super {} {}", chunk.read_string_literal(&name), bound);
+                        env.stack.push(bound);
+                    },
+                    None => {
+                        let msg = format!("Undefined property '{}'.", chunk.read_string_literal(&name));
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
+                }
                 ip += 2;
             },
             OpCode::Equal => {
                 let b = env.stack.pop();
                 let a = env.stack.pop();
-                env.stack.push(&Value::Bool(values_equal(&a, &b, &chunk)));
+                env.stack.push(Value::Bool(values_equal(&a, &b, chunk, env)));
                 dbg_if!(debug, "Equal {} {}", a, b);
                 ip += 1;
             },
-            OpCode::Greater => binary!(env, >, Value::Bool, ip, debug),
-            OpCode::Less => binary!(env, <, Value::Bool, ip, debug),
+            OpCode::Greater => compare!(env, chunk, opcode, >, ip, debug),
+            OpCode::Less => compare!(env, chunk, opcode, <, ip, debug),
             OpCode::Add => {
                 let b = env.stack.pop();
                 let a = env.stack.pop();
                 match (a, b) {
                     (Value::Number(a), Value::Number(b)) => {
-                        env.stack.push(&Value::Number(a + b));
-                        dbg!("Add numbers {} {}", a, b);
+                        env.stack.push(Value::Number(a + b));
+                        dbg_if!(debug, "Add numbers {} {}", a, b);
                     },
                     (Value::String(a), Value::String(b)) => {
-                        let a_str = chunk.read_string_literal(&a);
-                        let b_str = chunk.read_string_literal(&b);
+                        let a_str = resolve_string(&a, chunk, env);
+                        let b_str = resolve_string(&b, chunk, env);
                         let mut new_string = String::new();
-                        new_string.push_str(&a_str);
-                        new_string.push_str(&b_str);
-                        dbg!("Add strings {} {} {}", a_str, b_str, new_string);
+                        new_string.push_str(a_str);
+                        new_string.push_str(b_str);
+                        dbg_if!(debug, "Add strings {} {} {}", a_str, b_str, new_string);
 
                         let new_dynamic_string = env.dynamic_strings.add_string(&new_string).expect("Too many dynamic strings");
-                        env.stack.push(&Value::String(new_dynamic_string));
+                        env.stack.push(Value::String(new_dynamic_string));
                     },
-                    _ => {
-                        runtime_error(&mut env.stack, opcode, chunk.get_line(ip), "Operands must be two numbers or two strings");
-                        return InterpretResult::RuntimeError;
+                    (a, b) => {
+                        let msg = format!(
+                            "Operands must be two numbers or two strings, got {} and {}.",
+                            a.type_name(),
+                            b.type_name(),
+                        );
+                        return runtime_error(env, chunk, opcode, ip, &msg);
                     }
                 }
                 ip += 1;
             }
-            OpCode::Subtract => binary!(env, -, Value::Number, ip, debug),
-            OpCode::Multiply => binary!(env, *, Value::Number, ip, debug),
-            OpCode::Divide => binary!(env, /, Value::Number, ip, debug),
+            OpCode::Subtract => binary!(env, chunk, opcode, -, Value::Number, ip, debug),
+            OpCode::Multiply => binary!(env, chunk, opcode, *, Value::Number, ip, debug),
+            OpCode::Divide => binary!(env, chunk, opcode, /, Value::Number, ip, debug),
             OpCode::Not => {
-                let value = env.stack.pop();
-                env.stack.push(&Value::Bool(is_falsy(&value)));
-                dbg_if!(debug, "Not {}", value);
+                let result = is_falsy(env.stack.peek(0));
+                dbg_if!(debug, "Not {}", env.stack.peek(0));
+                env.stack.replace_top(Value::Bool(result));
                 ip += 1;
             },
             OpCode::Negate => {
                 if !env.stack.peek(0).is_number() {
-                    runtime_error(&mut env.stack, opcode, chunk.get_line(ip), "Operand must be a number");
-                    return InterpretResult::RuntimeError;
+                    let msg = format!("Operand must be a number, got {}.", env.stack.peek(0).type_name());
+                    return runtime_error(env, chunk, opcode, ip, &msg);
                 }
                 let value = env.stack.pop();
-                env.stack.push(&Value::Number(-value.as_number()));
+                env.stack.push(Value::Number(-value.as_number()));
                 dbg_if!(debug, "Negate {}", value);
                 ip += 1;
             },
+            OpCode::TypeOf => {
+                let value = env.stack.pop();
+                let type_name = env.dynamic_strings.add_string(value.type_name()).expect("Too many dynamic strings");
+                env.stack.push(Value::String(type_name));
+                dbg_if!(debug, "TypeOf {}", value);
+                ip += 1;
+            },
             OpCode::Print => {
                 let value = env.stack.pop();
                 dbg_if!(debug, "Print {}", value);
-                print_value(&value, &chunk, env);
+                print_value(&value, chunk, env);
+                ip += 1;
+            },
+            OpCode::EPrint => {
+                let value = env.stack.pop();
+                dbg_if!(debug, "EPrint {}", value);
+                let text = format_output(&value, chunk, env);
+                writeln!(env.errors, "{}", text).expect("Failed to write error");
+                ip += 1;
+            },
+            OpCode::PrintElement => {
+                let value = env.stack.pop();
+                dbg_if!(debug, "Print element {}", value);
+                let text = format_output(&value, chunk, env);
+                write!(env.output, "{} ", text).expect("Failed to write output");
+                ip += 1;
+            },
+            OpCode::PushHandler => {
+                let offset = (chunk.byte(ip + 1) as usize) << 8 | chunk.byte(ip + 2) as usize;
+                dbg_if!(debug, "Push Handler {}", offset);
+                ip += 3;
+                env.handlers.push(Handler {
+                    frames: env.frames.len(),
+                    stack_len: env.stack.len(),
+                    catch_ip: ip + offset,
+                });
+            },
+            OpCode::PopHandler => {
+                dbg_if!(debug, "Pop Handler");
+                env.handlers.pop();
+                ip += 1;
+            },
+            OpCode::Throw => {
+                let value = env.stack.pop();
+                dbg_if!(debug, "Throw {}", value);
+                let msg = format_value(&value, chunk, env, env.number_precision);
+                return runtime_error(env, chunk, opcode, ip, &msg);
+            },
+            OpCode::Jump => {
+                let offset = (chunk.byte(ip + 1) as usize) << 8 | chunk.byte(ip + 2) as usize;
+                dbg_if!(debug, "Jump {}", offset);
+                ip += 3 + offset;
+            },
+            OpCode::JumpIfFalse => {
+                let offset = (chunk.byte(ip + 1) as usize) << 8 | chunk.byte(ip + 2) as usize;
+                dbg_if!(debug, "Jump If False {} {}", offset, env.stack.peek(0));
+                ip += 3;
+                if is_falsy(env.stack.peek(0)) {
+                    ip += offset;
+                }
+            },
+            OpCode::JumpIfNotNil => {
+                let offset = (chunk.byte(ip + 1) as usize) << 8 | chunk.byte(ip + 2) as usize;
+                dbg_if!(debug, "Jump If Not Nil {} {}", offset, env.stack.peek(0));
+                ip += 3;
+                if !matches!(env.stack.peek(0), Value::Nil) {
+                    ip += offset;
+                }
+            },
+            OpCode::PopJumpIfFalse => {
+                let offset = (chunk.byte(ip + 1) as usize) << 8 | chunk.byte(ip + 2) as usize;
+                let condition = env.stack.pop();
+                dbg_if!(debug, "Pop Jump If False {} {}", offset, condition);
+                ip += 3;
+                if is_falsy(&condition) {
+                    ip += offset;
+                }
+            },
+            OpCode::Loop => {
+                let offset = (chunk.byte(ip + 1) as usize) << 8 | chunk.byte(ip + 2) as usize;
+                dbg_if!(debug, "Loop {}", offset);
+                ip = ip + 3 - offset;
+            },
+            OpCode::Call => {
+                let arg_count = chunk.byte(ip + 1) as usize;
+                let callee = env.stack.peek(arg_count).clone();
+                dbg_if!(debug, "Call {} {}", callee, arg_count);
+                match env.call_value(callee, arg_count, ip + 2, chunk) {
+                    Ok(next) => {
+                        ip = next;
+                        slot_base = env.frame().slot;
+                    },
+                    Err(msg) => {
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
+                }
+            },
+            OpCode::Invoke => {
+                let name = StringId::new_literal_id(chunk.byte(ip + 1));
+                let arg_count = chunk.byte(ip + 2) as usize;
+                dbg_if!(debug, "Invoke {} {}", chunk.read_string_literal(&name), arg_count);
+                match env.invoke(&name, arg_count, ip + 3, chunk) {
+                    Ok(next) => {
+                        ip = next;
+                        slot_base = env.frame().slot;
+                    },
+                    Err(msg) => {
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
+                }
+            },
+            OpCode::SuperInvoke => {
+                let name = StringId::new_literal_id(chunk.byte(ip + 1));
+                let arg_count = chunk.byte(ip + 2) as usize;
+                let superclass = match env.stack.pop() {
+                    Value::Class(class) => class,
+                    _ => panic!("Superclass must be a class"),
+                };
+                dbg_if!(debug, "Super Invoke {} {}", chunk.read_string_literal(&name), arg_count);
+                match env.invoke_from_class(superclass, &name, arg_count, ip + 3, chunk) {
+                    Ok(next) => {
+                        ip = next;
+                        slot_base = env.frame().slot;
+                    },
+                    Err(msg) => {
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
+                }
+            },
+            OpCode::Closure => {
+                let function = match chunk.read_constant(ip + 1) {
+                    Value::Function(function) => function.clone(),
+                    _ => panic!("Closure operand must be a function"),
+                };
+                ip += 2;
+
+                let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                for _ in 0..function.upvalue_count {
+                    let is_local = chunk.byte(ip) == 1;
+                    let index = chunk.byte(ip + 1) as usize;
+                    let upvalue = if is_local {
+                        let slot = slot_base + index;
+                        env.capture_upvalue(slot)
+                    } else {
+                        env.frame_upvalue(index)
+                    };
+                    upvalues.push(upvalue);
+                    ip += 2;
+                }
+
+                dbg_if!(debug, "Closure {} with {} upvalues", function, upvalues.len());
+                let closure = env.closures.add(Closure { function, upvalues });
+                env.stack.push(Value::Closure(closure));
+            },
+            OpCode::CloseUpvalue => {
+                let top = env.stack.len() - 1;
+                env.close_upvalues(top);
+                env.stack.pop();
+                dbg_if!(debug, "Close Upvalue {}", top);
                 ip += 1;
             },
             OpCode::Return => {
-                if env.stack.is_empty() {
-                    dbg_if!(debug, "Stack Empty. Return Nothing")
-                } else {
-                    let value = env.stack.pop();
-                    dbg_if!(debug, "Return {}", value);
+                let result = env.stack.pop();
+                let frame = env.frames.pop().expect("No call frame");
+                dbg_if!(debug, "Return {} from {}", result, frame.closure);
+                env.close_upvalues(frame.slot);
+                // A `return` inside a try block leaves it.
+                while env.handlers.last().is_some_and(|handler| handler.frames > env.frames.len()) {
+                    env.handlers.pop();
+                }
+
+                if env.frames.is_empty() {
+                    env.stack.pop(); // Pop the script function
+                    return InterpretResult::Ok;
+                }
+
+                env.stack.truncate(frame.slot);
+                env.stack.push(result);
+                ip = env.frame().ip;
+                slot_base = env.frame().slot;
+            },
+            OpCode::BuildList => {
+                let count = chunk.byte(ip + 1) as usize;
+                let start = env.stack.len() - count;
+                let items = (start..env.stack.len()).map(|i| env.stack.get(i).clone()).collect();
+                env.stack.truncate(start);
+
+                let list = env.lists.add(List { items });
+                dbg_if!(debug, "Build List {} with {} items", list, count);
+                env.stack.push(Value::List(list));
+                ip += 2;
+            },
+            OpCode::BuildMap => {
+                let count = chunk.byte(ip + 1) as usize;
+                let start = env.stack.len() - count * 2;
+                let mut entries = HashMap::with_capacity(count);
+                for i in 0..count {
+                    let key = match map_key(env.stack.get(start + i * 2), chunk, env) {
+                        Ok(key) => key,
+                        Err(msg) => return runtime_error(env, chunk, opcode, ip, &msg),
+                    };
+                    entries.insert(key, env.stack.get(start + i * 2 + 1).clone());
+                }
+                env.stack.truncate(start);
+
+                let map = env.maps.add(Map { entries });
+                dbg_if!(debug, "Build Map {} with {} entries", map, count);
+                env.stack.push(Value::Map(map));
+                ip += 2;
+            },
+            OpCode::GetIndex => {
+                let value = match env.stack.peek(1) {
+                    Value::List(list) => {
+                        let items = &env.lists.get(list).items;
+                        match list_index(env.stack.peek(0), items.len()) {
+                            Ok(index) => items[index].clone(),
+                            Err(msg) => return runtime_error(env, chunk, opcode, ip, &msg),
+                        }
+                    },
+                    Value::Map(map) => {
+                        let key = match map_key(env.stack.peek(0), chunk, env) {
+                            Ok(key) => key,
+                            Err(msg) => return runtime_error(env, chunk, opcode, ip, &msg),
+                        };
+
+                        match env.maps.get(map).entries.get(&key) {
+                            Some(value) => value.clone(),
+                            None => {
+                                let msg = format!("Undefined key {}.", format_key(&key, env.number_precision));
+                                return runtime_error(env, chunk, opcode, ip, &msg);
+                            }
+                        }
+                    },
+                    other => {
+                        let msg = format!("Can only index lists and maps, got {}.", other.type_name());
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
                 };
-                return InterpretResult::Ok
+
+                env.stack.pop(); // Pop the index
+                env.stack.pop(); // Pop the list or map
+                dbg_if!(debug, "Get Index {}", value);
+                env.stack.push(value);
+                ip += 1;
+            },
+            OpCode::SetIndex => {
+                let value = env.stack.peek(0).clone();
+                match env.stack.peek(2) {
+                    Value::List(list) => {
+                        let list = *list;
+                        let index = match list_index(env.stack.peek(1), env.lists.get(&list).items.len()) {
+                            Ok(index) => index,
+                            Err(msg) => return runtime_error(env, chunk, opcode, ip, &msg),
+                        };
+                        env.lists.get_mut(&list).items[index] = value.clone();
+                    },
+                    Value::Map(map) => {
+                        let map = *map;
+                        let key = match map_key(env.stack.peek(1), chunk, env) {
+                            Ok(key) => key,
+                            Err(msg) => return runtime_error(env, chunk, opcode, ip, &msg),
+                        };
+                        env.maps.get_mut(&map).entries.insert(key, value.clone());
+                    },
+                    other => {
+                        let msg = format!("Can only index lists and maps, got {}.", other.type_name());
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
+                }
+
+                dbg_if!(debug, "Set Index {}", value);
+                env.stack.truncate(env.stack.len() - 3);
+                env.stack.push(value);
+                ip += 1;
+            },
+            OpCode::Unpack => {
+                let count = chunk.byte(ip + 1) as usize;
+                let id = match env.stack.peek(0) {
+                    Value::List(id) => *id,
+                    value => {
+                        let msg = format!("Only lists can be unpacked, got {}.", value.type_name());
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    },
+                };
+
+                let items = &env.lists.get(&id).items;
+                if items.len() != count {
+                    let msg = format!("Expected {} values to unpack, got {}.", count, items.len());
+                    return runtime_error(env, chunk, opcode, ip, &msg);
+                }
+
+                dbg_if!(debug, "Unpack {} from {}", count, id);
+                let items = items.clone();
+                env.stack.pop();
+                for item in items {
+                    env.stack.push(item);
+                }
+                ip += 2;
+            },
+            OpCode::GetIter => {
+                let iterable = env.stack.pop();
+                let list = match iterable {
+                    Value::List(list) => list,
+                    Value::Map(map) => {
+                        let mut keys: Vec<ValueKey> = env.maps.get(&map).entries.keys().cloned().collect();
+                        keys.sort();
+                        let mut items = Vec::with_capacity(keys.len());
+                        for key in keys {
+                            items.push(match key {
+                                ValueKey::Nil => Value::Nil,
+                                ValueKey::Bool(b) => Value::Bool(b),
+                                ValueKey::Number(bits) => Value::Number(f64::from_bits(bits)),
+                                ValueKey::String(string) => {
+                                    Value::String(env.dynamic_strings.add_string(&string).expect("Too many dynamic strings"))
+                                },
+                            });
+                        }
+                        env.lists.add(List { items })
+                    },
+                    other => {
+                        let msg = format!("Can only iterate over lists and maps, got {}.", other.type_name());
+                        return runtime_error(env, chunk, opcode, ip, &msg);
+                    }
+                };
+
+                dbg_if!(debug, "Get Iter {}", list);
+                env.stack.push(Value::List(list));
+                ip += 1;
+            },
+            OpCode::IterNext => {
+                let slot = chunk.byte(ip + 1) as usize;
+                let jump = (chunk.byte(ip + 2) as usize) << 8 | chunk.byte(ip + 3) as usize;
+                let list = match env.stack.get(slot_base + slot) {
+                    Value::List(list) => *list,
+                    _ => panic!("Iterable must be a list"),
+                };
+                // The counter sits in the slot right after the iterable.
+                let index = env.stack.get(slot_base + slot + 1).as_number() as usize;
+
+                match env.lists.get(&list).items.get(index) {
+                    Some(item) => {
+                        let item = item.clone();
+                        dbg_if!(debug, "Iter Next {} {}", index, item);
+                        env.stack.set(slot_base + slot + 1, Value::Number((index + 1) as f64));
+                        env.stack.push(item);
+                        ip += 4;
+                    },
+                    None => {
+                        dbg_if!(debug, "Iter Done");
+                        ip += 4 + jump;
+                    },
+                }
+            },
+            OpCode::Class => {
+                let name = StringId::new_literal_id(chunk.byte(ip + 1));
+                dbg_if!(debug, "Class {}", chunk.read_string_literal(&name));
+                let class = env.classes.add(Class { name, methods: HashMap::new(), initializer: None });
+                env.stack.push(Value::Class(class));
+                ip += 2;
+            },
+            OpCode::Inherit => {
+                let superclass = match env.stack.peek(1) {
+                    Value::Class(class) => *class,
+                    _ => {
+                        return runtime_error(env, chunk, opcode, ip, "Superclass must be a class.");
+                    }
+                };
+                let subclass = match env.stack.peek(0) {
+                    Value::Class(class) => *class,
+                    _ => panic!("Subclass must be a class"),
+                };
+
+                // Copy-down inheritance: methods defined in the subclass body will override these.
+                let methods = env.classes.get(&superclass).methods.clone();
+                let initializer = env.classes.get(&superclass).initializer;
+                let subclass = env.classes.get_mut(&subclass);
+                subclass.methods.extend(methods);
+                subclass.initializer = initializer;
+                dbg_if!(debug, "Inherit");
+                env.stack.pop(); // Pop the subclass
+                ip += 1;
+            },
+            OpCode::Method => {
+                let name = StringId::new_literal_id(chunk.byte(ip + 1));
+                let method = match env.stack.peek(0) {
+                    Value::Closure(closure) => *closure,
+                    _ => panic!("Method must be a closure"),
+                };
+                let class = match env.stack.peek(1) {
+                    Value::Class(class) => *class,
+                    _ => panic!("Method must be defined on a class"),
+                };
+
+                dbg_if!(debug, "Method {}", chunk.read_string_literal(&name));
+                let is_initializer = chunk.read_string_literal(&name) == "init";
+                let class = env.classes.get_mut(&class);
+                class.methods.insert(name, method);
+                if is_initializer {
+                    class.initializer = Some(method);
+                }
+                env.stack.pop(); // Pop the method
+                ip += 2;
             },
         }
     }
 }
 
-fn print_value(value: &Value, chunk: &Chunk, env: &Env) {
+/// `log(x)` prints `x` like `print` and returns it, so it can observe evaluation order.
+fn native_log(args: &[Value], chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    print_value(&args[0], chunk, env);
+    Ok(args[0].clone())
+}
+
+/// `len(s)` is the number of Unicode scalar values in `s`, not its byte length,
+/// so `len("héllo")` is 5. For a list it's the number of items.
+fn native_len(args: &[Value], chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    match &args[0] {
+        Value::String(id) => {
+            let string = resolve_string(id, chunk, env);
+
+            Ok(Value::Number(string.chars().count() as f64))
+        },
+        Value::List(list) => Ok(Value::Number(env.lists.get(list).items.len() as f64)),
+        other => Err(format!("len() expects a string or a list, got {}.", other.type_name())),
+    }
+}
+
+/// `substr(s, start, end)` returns the characters of `s` in `start..end`. Like `len`, it counts
+/// Unicode scalar values. Negative indices count from the end, so `substr(s, -3, len(s))` is the
+/// last three characters. Indices past the end are clamped, and an empty string comes back if
+/// `start` isn't before `end`.
+fn native_substr(args: &[Value], chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    let id = match &args[0] {
+        Value::String(id) => id,
+        other => return Err(format!("substr() expects a string, got {}.", other.type_name())),
+    };
+
+    let string = resolve_string(id, chunk, env);
+    let len = string.chars().count();
+    let start = substr_index(&args[1], len)?;
+    let end = substr_index(&args[2], len)?;
+    let slice: String = string.chars().skip(start).take(end.saturating_sub(start)).collect();
+
+    let id = env.dynamic_strings.add_string(&slice)?;
+    Ok(Value::String(id))
+}
+
+fn substr_index(value: &Value, len: usize) -> Result<usize, String> {
     match value {
-        Value::Nil => println!("nil"),
-        Value::Bool(b) => println!("{}", b),
-        Value::Number(n) => println!("{}", n),
+        Value::Number(n) if n.is_nan() || (n.is_finite() && n.fract() != 0.0) => Err(format!("substr() index must be a whole number, got {}.", format_number(*n, None))),
+        Value::Number(n) if *n < 0.0 => {
+            let resolved = *n + len as f64;
+            if resolved < 0.0 {
+                return Err(format!("substr() index {} is out of range for a string of length {}.", format_number(*n, None), len));
+            }
+
+            Ok(resolved as usize)
+        },
+        // Saturates for huge values, which clamps them like any other index past the end.
+        Value::Number(n) => Ok(*n as usize),
+        other => Err(format!("substr() index must be a number, got {}.", other.type_name())),
+    }
+}
+
+/// `clone(x)` deep-copies lists and maps, so changing the copy leaves `x` alone.
+/// Every other value comes back as it is.
+fn native_clone(args: &[Value], _chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    Ok(deep_clone(&args[0], env, &mut Copies::default()))
+}
+
+/// Copies made so far by `deep_clone`, by the id of the original. A list or map reached twice
+/// is copied once, so shared and self-referencing structures keep their shape.
+#[derive(Default)]
+struct Copies {
+    lists: HashMap<ObjectId, ObjectId>,
+    maps: HashMap<ObjectId, ObjectId>,
+}
+
+fn deep_clone(value: &Value, env: &mut Env, copies: &mut Copies) -> Value {
+    match value {
+        Value::List(list) => {
+            if let Some(copy) = copies.lists.get(list) {
+                return Value::List(*copy);
+            }
+
+            let copy = env.lists.add(List { items: Vec::new() });
+            copies.lists.insert(*list, copy);
+            let items = env.lists.get(list).items.clone();
+            let items = items.iter().map(|item| deep_clone(item, env, copies)).collect();
+            env.lists.get_mut(&copy).items = items;
+
+            Value::List(copy)
+        },
+        Value::Map(map) => {
+            if let Some(copy) = copies.maps.get(map) {
+                return Value::Map(*copy);
+            }
+
+            let copy = env.maps.add(Map { entries: HashMap::new() });
+            copies.maps.insert(*map, copy);
+            let entries = env.maps.get(map).entries.clone();
+            let entries = entries.into_iter().map(|(key, value)| (key, deep_clone(&value, env, copies))).collect();
+            env.maps.get_mut(&copy).entries = entries;
+
+            Value::Map(copy)
+        },
+        other => other.clone(),
+    }
+}
+
+/// Checks that argument `index` of the native `name` is a number.
+fn number_arg(args: &[Value], index: usize, name: &str) -> Result<f64, String> {
+    match &args[index] {
+        Value::Number(n) => Ok(*n),
+        other => Err(format!("{}() expects a number, got {}.", name, other.type_name())),
+    }
+}
+
+/// Defines a native applying an `f64` method to its only argument.
+macro_rules! math_native {
+    ($native: ident, $name: expr, $f: expr) => {
+        fn $native(args: &[Value], _chunk: &Chunk, _env: &mut Env) -> Result<Value, String> {
+            Ok(Value::Number($f(number_arg(args, 0, $name)?)))
+        }
+    };
+}
+
+math_native!(native_floor, "floor", f64::floor);
+math_native!(native_ceil, "ceil", f64::ceil);
+// Halfway cases round away from zero, so `round(-2.5)` is -3.
+math_native!(native_round, "round", f64::round);
+math_native!(native_abs, "abs", f64::abs);
+// The square root of a negative number is `nan`.
+math_native!(native_sqrt, "sqrt", f64::sqrt);
+
+/// `min(a, b)` is the smaller of two numbers, and `min(list)` the smallest number in a list.
+fn native_min(args: &[Value], _chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    extreme(args, env, "min", f64::min)
+}
+
+/// `max(a, b)` is the larger of two numbers, and `max(list)` the largest number in a list.
+fn native_max(args: &[Value], _chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    extreme(args, env, "max", f64::max)
+}
+
+/// Folds either two number arguments or the items of a single non-empty list with `pick`.
+fn extreme(args: &[Value], env: &Env, name: &str, pick: fn(f64, f64) -> f64) -> Result<Value, String> {
+    match args {
+        [Value::List(list)] => {
+            let items = &env.lists.get(list).items;
+            if items.is_empty() {
+                return Err(format!("{}() expects a non-empty list.", name));
+            }
+
+            let mut result = number_arg(items, 0, name)?;
+            for index in 1..items.len() {
+                result = pick(result, number_arg(items, index, name)?);
+            }
+
+            Ok(Value::Number(result))
+        },
+        [_, _] => Ok(Value::Number(pick(number_arg(args, 0, name)?, number_arg(args, 1, name)?))),
+        [_] => Err(format!("{}() expects a list or two numbers, got {}.", name, args[0].type_name())),
+        _ => Err(format!("Expected 1 or 2 arguments but got {}.", args.len())),
+    }
+}
+
+/// `parseNumber(s)` reads a decimal number like `-3.14` or `2e10`, ignoring whitespace around it.
+/// Anything else, including `inf`, `nan` and numbers too large for a float, gives `nil`.
+fn native_parse_number(args: &[Value], chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    let id = match &args[0] {
+        Value::String(id) => id,
+        other => return Err(format!("parseNumber() expects a string, got {}.", other.type_name())),
+    };
+    let string = resolve_string(id, chunk, env);
+
+    match string.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(Value::Number(n)),
+        _ => Ok(Value::Nil),
+    }
+}
+
+/// `readLine()` reads the next line of input without its line ending, or gives `nil` at the end of input.
+fn native_read_line(_args: &[Value], _chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    let mut line = String::new();
+    let read = match env.input.as_mut() {
+        Some(input) => input.read_line(&mut line),
+        None => io::stdin().read_line(&mut line),
+    };
+
+    match read {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            let id = env.dynamic_strings.add_string(line)?;
+            Ok(Value::String(id))
+        },
+        Err(err) => Err(format!("readLine() failed: {}.", err)),
+    }
+}
+
+/// `fmt(format, ...)` fills each `{}` in `format` with the next argument, formatted like `print`.
+/// `{{` and `}}` stand for literal braces. The number of placeholders must match the arguments.
+fn native_fmt(args: &[Value], chunk: &Chunk, env: &mut Env) -> Result<Value, String> {
+    let format = match args.first() {
+        Some(Value::String(id)) => resolve_string(id, chunk, env).to_string(),
+        Some(other) => return Err(format!("fmt() expects a format string, got {}.", other.type_name())),
+        None => return Err(String::from("fmt() expects a format string.")),
+    };
+    let values = &args[1..];
+
+    let mut result = String::with_capacity(format.len());
+    let mut placeholders = 0;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                result.push(c);
+            },
+            ('{', Some('}')) => {
+                chars.next();
+                if let Some(value) = values.get(placeholders) {
+                    result.push_str(&format_value(value, chunk, env, env.number_precision));
+                }
+                placeholders += 1;
+            },
+            ('{', _) | ('}', _) => return Err(format!("fmt() found an unmatched '{}' in the format string.", c)),
+            _ => result.push(c),
+        }
+    }
+
+    if placeholders != values.len() {
+        return Err(format!("fmt() has {} placeholders but got {} arguments.", placeholders, values.len()));
+    }
+
+    let id = env.dynamic_strings.add_string(&result)?;
+    Ok(Value::String(id))
+}
+
+fn print_value(value: &Value, chunk: &Chunk, env: &mut Env) {
+    let text = format_output(value, chunk, env);
+    writeln!(env.output, "{}", text).expect("Failed to write output");
+}
+
+/// Formats a value the way `print` writes it, as JSON when `print_json` is on.
+fn format_output(value: &Value, chunk: &Chunk, env: &Env) -> String {
+    if env.print_json {
+        format_json(value, chunk, env, env.number_precision, &mut Vec::new())
+    } else {
+        format_value(value, chunk, env, env.number_precision)
+    }
+}
+
+/// Nil is `null`, and numbers JSON can't hold (NaN and the infinities) are too. Map keys
+/// become strings since JSON only has string keys. Values with no JSON counterpart, like
+/// functions and instances, are written as strings of their usual form.
+fn format_json(value: &Value, chunk: &Chunk, env: &Env, precision: Option<usize>, printing: &mut Vec<Value>) -> String {
+    match value {
+        Value::Nil => String::from("null"),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) if !n.is_finite() => String::from("null"),
+        Value::Number(n) => format_number(*n, precision),
+        Value::List(list) => {
+            // A list containing itself can't be written as JSON, so the inner reference is `null`.
+            if printing.iter().any(|outer| values_equal(outer, value, chunk, env)) {
+                return String::from("null");
+            }
+
+            printing.push(value.clone());
+            let items: Vec<String> = env.lists.get(list).items.iter()
+                .map(|item| format_json(item, chunk, env, precision, printing))
+                .collect();
+            printing.pop();
+
+            format!("[{}]", items.join(","))
+        },
+        Value::Map(map) => {
+            if printing.iter().any(|outer| values_equal(outer, value, chunk, env)) {
+                return String::from("null");
+            }
+
+            printing.push(value.clone());
+            let mut entries: Vec<_> = env.maps.get(map).entries.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            let entries: Vec<String> = entries.into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        ValueKey::String(string) => json_string(string),
+                        key => json_string(&format_key(key, precision)),
+                    };
+                    format!("{}:{}", key, format_json(value, chunk, env, precision, printing))
+                })
+                .collect();
+            printing.pop();
+
+            format!("{{{}}}", entries.join(","))
+        },
+        _ => json_string(&format_value(value, chunk, env, precision)),
+    }
+}
+
+fn json_string(string: &str) -> String {
+    let mut json = String::with_capacity(string.len() + 2);
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            '\u{8}' => json.push_str("\\b"),
+            '\u{c}' => json.push_str("\\f"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Reads a string from whichever storage its id belongs to: the chunk's literals or the
+/// strings made at runtime.
+fn resolve_string<'a>(id: &StringId, chunk: &'a Chunk, env: &'a Env) -> &'a str {
+    if id.is_literal() {
+        chunk.read_string_literal(id)
+    } else {
+        env.dynamic_strings.get_string(id)
+    }
+}
+
+fn format_value(value: &Value, chunk: &Chunk, env: &Env, precision: Option<usize>) -> String {
+    format_value_in(value, chunk, env, precision, &mut Vec::new())
+}
+
+/// `printing` holds the lists and maps being printed further up, so one containing itself
+/// prints `[...]` or `{...}` instead of recursing forever.
+fn format_value_in(value: &Value, chunk: &Chunk, env: &Env, precision: Option<usize>, printing: &mut Vec<Value>) -> String {
+    match value {
+        Value::Nil => String::from("nil"),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => format_number(*n, precision),
         Value::String(id) => {
-            let string = if id.is_literal() {
-                chunk.read_string_literal(id)
-            } else {
-                env.dynamic_strings.get_string(id)
-            };
+            let string = resolve_string(id, chunk, env);
 
-            println!("{}", string);
+            String::from(string)
         }
+        Value::Function(function) => format_function(function, chunk),
+        Value::Native(_) => String::from("<native fn>"),
+        Value::Closure(closure) => format_function(&env.closures.get(closure).function, chunk),
+        Value::Class(class) => String::from(chunk.read_string_literal(&env.classes.get(class).name)),
+        Value::Instance(instance) => {
+            let class = env.classes.get(&env.instances.get(instance).class);
+            format!("{} instance", chunk.read_string_literal(&class.name))
+        },
+        Value::BoundMethod(bound) => {
+            let method = env.bound_methods.get(bound).method;
+            format_function(&env.closures.get(&method).function, chunk)
+        },
+        Value::List(list) => {
+            if printing.iter().any(|outer| values_equal(outer, value, chunk, env)) {
+                return String::from("[...]");
+            }
+
+            printing.push(value.clone());
+            let items: Vec<String> = env.lists.get(list).items.iter()
+                .map(|item| format_element(item, chunk, env, precision, printing))
+                .collect();
+            printing.pop();
+
+            format!("[{}]", items.join(", "))
+        },
+        Value::Map(map) => {
+            if printing.iter().any(|outer| values_equal(outer, value, chunk, env)) {
+                return String::from("{...}");
+            }
+
+            printing.push(value.clone());
+            let mut entries: Vec<_> = env.maps.get(map).entries.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            let entries: Vec<String> = entries.into_iter()
+                .map(|(key, value)| format!("{}: {}", format_key(key, precision), format_element(value, chunk, env, precision, printing)))
+                .collect();
+            printing.pop();
+
+            format!("{{{}}}", entries.join(", "))
+        },
     }
+}
 
+/// Formats a value inside a list or map, where strings are quoted.
+fn format_element(value: &Value, chunk: &Chunk, env: &Env, precision: Option<usize>, printing: &mut Vec<Value>) -> String {
+    match value {
+        Value::String(_) => format!("\"{}\"", format_value_in(value, chunk, env, precision, printing)),
+        _ => format_value_in(value, chunk, env, precision, printing),
+    }
 }
 
-fn is_falsy(value: &Value) -> bool {
+/// Checks that `value` is a whole number indexing into a list of `len` items.
+/// Negative indices count from the end, so `-1` is the last item and `-len` the first.
+fn list_index(value: &Value, len: usize) -> Result<usize, String> {
+    let n = match value {
+        Value::Number(n) => *n,
+        other => return Err(format!("List index must be a number, got {}.", other.type_name())),
+    };
+
+    if n.fract() != 0.0 || n.is_nan() {
+        return Err(format!("List index must be a whole number, got {}.", format_number(n, None)));
+    }
+    let resolved = if n < 0.0 { n + len as f64 } else { n };
+    if resolved < 0.0 || resolved >= len as f64 {
+        return Err(format!("List index {} is out of range for a list of length {}.", format_number(n, None), len));
+    }
+
+    Ok(resolved as usize)
+}
+
+/// Strings are quoted, so the key `"1"` can be told apart from the key `1`.
+fn format_key(key: &ValueKey, precision: Option<usize>) -> String {
+    match key {
+        ValueKey::Nil => String::from("nil"),
+        ValueKey::Bool(b) => b.to_string(),
+        ValueKey::Number(bits) => format_number(f64::from_bits(*bits), precision),
+        ValueKey::String(string) => format!("\"{}\"", string),
+    }
+}
+
+/// Map keys can be nil, bools, numbers other than NaN, and strings.
+fn map_key(value: &Value, chunk: &Chunk, env: &Env) -> Result<ValueKey, String> {
     match value {
-        Value::Nil => true,
-        Value::Bool(false) => true,
-        _ => false,
+        Value::Nil => Ok(ValueKey::Nil),
+        Value::Bool(b) => Ok(ValueKey::Bool(*b)),
+        Value::Number(n) => ValueKey::number(*n).ok_or_else(|| String::from("NaN can't be a map key.")),
+        Value::String(id) => {
+            let string = resolve_string(id, chunk, env);
+
+            Ok(ValueKey::String(String::from(string)))
+        },
+        other => Err(format!("Map keys must be nil, bools, numbers or strings, got {}.", other.type_name())),
+    }
+}
+
+fn format_function(function: &Function, chunk: &Chunk) -> String {
+    match &function.name {
+        Some(name) => format!("<fn {}>", chunk.read_string_literal(name)),
+        None => String::from("<fn>"),
     }
 }
 
-fn values_equal(a: &Value, b: &Value, chunk: &Chunk) -> bool {
+fn is_falsy(value: &Value) -> bool {
+    matches!(value, Value::Nil | Value::Bool(false))
+}
+
+fn values_equal(a: &Value, b: &Value, chunk: &Chunk, env: &Env) -> bool {
     match (a, b) {
         (Value::Nil, Value::Nil) => true,
         (Value::Bool(a), Value::Bool(b)) => a == b,
         (Value::Number(a), Value::Number(b)) => a == b,
+        // Literals are deduplicated per chunk, so equal literal ids mean equal strings.
+        (Value::String(a), Value::String(b)) if a.is_literal() && b.is_literal() => a == b,
         (Value::String(a), Value::String(b)) => {
-            let a_str = chunk.read_string_literal(a);
-            let b_str = chunk.read_string_literal(b);
+            let a_str = resolve_string(a, chunk, env);
+            let b_str = resolve_string(b, chunk, env);
             a_str == b_str
         }
+        (Value::Function(a), Value::Function(b)) => a.entry == b.entry,
+        (Value::Native(a), Value::Native(b)) => a.name == b.name,
+        (Value::Closure(a), Value::Closure(b)) => a == b,
+        (Value::Class(a), Value::Class(b)) => a == b,
+        (Value::Instance(a), Value::Instance(b)) => a == b,
+        (Value::BoundMethod(a), Value::BoundMethod(b)) => a == b,
+        (Value::List(a), Value::List(b)) => a == b,
+        (Value::Map(a), Value::Map(b)) => a == b,
         _ => false,
     }
 }
 
-fn runtime_error(stack: &mut Stack, opcode: OpCode, line: u32, message: &str) {
-    eprintln!("[line {}] Runtime Error: {} {}", line, opcode, message);
-    stack.reset();
+/// Builds the error for a failure at `ip`. Unless a `try` block will catch it, the error is
+/// also reported and the stack is cleared.
+fn runtime_error(env: &mut Env, chunk: &Chunk, opcode: OpCode, ip: usize, message: &str) -> InterpretResult {
+    let line = chunk.line_at_instruction(ip);
+    let caught = !env.handlers.is_empty();
+    if !caught {
+        writeln!(env.errors, "[line {}] Runtime Error: {} {}", line, opcode, message).expect("Failed to write error");
+        if let Some(underline) = chunk.render_span(ip) {
+            writeln!(env.errors, "{}", underline).expect("Failed to write error");
+        }
+    }
+
+    let mut trace = Vec::with_capacity(env.frames.len());
+    for (depth, frame) in env.frames.iter().enumerate().rev() {
+        // Outer frames are paused right after their call instruction.
+        let frame_line = if depth == env.frames.len() - 1 {
+            line
+        } else {
+            chunk.line_at_instruction(frame.ip - 1)
+        };
+
+        let function = &env.closures.get(&frame.closure).function;
+        let location = match &function.name {
+            Some(name) => format!("{}()", chunk.read_string_literal(name)),
+            None if depth == 0 => String::from("script"),
+            None => String::from("<fn>()"),
+        };
+
+        trace.push(format!("[line {}] in {}", frame_line, location));
+    }
+
+    if !caught {
+        for entry in trace.iter() {
+            writeln!(env.errors, "{}", entry).expect("Failed to write error");
+        }
+
+        env.reset_execution();
+    }
+
+    InterpretResult::RuntimeError(RuntimeError {
+        message: String::from(message),
+        line,
+        trace,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn garbage_collection_keeps_the_heap_bounded() {
+        let mut vm = VM::new(false);
+        // Every iteration makes a 200-byte string that's garbage by the next one,
+        // so the loop allocates several times the initial GC threshold in total.
+        let source = format!(r#"
+            var kept = "keep" + "me";
+            var part = "{}";
+            var i = 0;
+            while (i < 25000) {{
+                var garbage = part + part;
+                i = i + 1;
+            }}
+        "#, "x".repeat(100));
+
+        assert!(matches!(vm.interpret(&source), InterpretResult::Ok));
+        assert!(vm.env.heap_size() <= 2 * GC_INITIAL_THRESHOLD);
+        assert!(vm.dump_globals().contains("kept = keepme\n"));
+    }
+
+    /// A function whose frame fills nearly all of its 256 slots, recursing `n` levels deeper.
+    fn deep_recursion(n: usize) -> String {
+        let locals: String = (0..250).map(|i| format!("var a{} = {};", i, i)).collect();
+        format!("fun f(n) {{ {} if (n > 0) f(n - 1); return a249; }} print f({});", locals, n)
+    }
+
+    #[test]
+    fn recursion_fills_the_reserved_stack_up_to_the_limit() {
+        let mut vm = VM::new(false);
+        vm.set_output(Box::new(io::sink()));
+        vm.set_errors(Box::new(io::sink()));
+        // The script's frame plus FRAMES_MAX - 1 calls.
+        let levels = FRAMES_MAX - 2;
+
+        assert!(matches!(vm.interpret(&deep_recursion(levels)), InterpretResult::Ok));
+        assert_eq!(vm.env.stack.values.capacity(), STACK_MAX);
+
+        match vm.interpret(&deep_recursion(levels + 1)) {
+            InterpretResult::RuntimeError(error) => assert_eq!(error.message, "Stack overflow."),
+            _ => panic!("Expected a stack overflow"),
+        }
+        assert_eq!(vm.env.stack.values.capacity(), STACK_MAX);
+    }
 }
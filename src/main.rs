@@ -1,18 +1,48 @@
-use crate::lox::vm::interpret;
+use clox_rs::lox::vm::{TraceOptions, VM};
+use clox_rs::lox::{dump_file, repl, run_file, run_files, run_stdin, VERSION};
 
-use std::fs::read_to_string;
-
-pub mod lox {
-    pub mod chunk;
-    pub mod compiler;
-    pub mod scanner;
-    pub mod object;
-    pub mod value;
-    pub mod vm;
-}
+use std::env;
+use std::io;
+use std::process::exit;
 
 fn main() {
-    let file = read_to_string("src/scripts/main.lox").unwrap();
+    let mut dump = false;
+    let mut trace = false;
+    let mut json = false;
+    let mut args: Vec<String> = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--dump" => dump = true,
+            "--trace" => trace = true,
+            "--json" => json = true,
+            "--version" => {
+                println!("clox-rs {}", VERSION);
+                return;
+            },
+            _ => args.push(arg),
+        }
+    }
+
+    let mut vm = VM::new(false);
+    vm.print_json = json;
+    if trace {
+        vm.trace = TraceOptions { disassemble: true, instructions: true };
+    }
 
-    interpret(&file, true);
+    match args.as_slice() {
+        [] if !dump => repl(&mut vm, io::stdin().lock(), io::stdout()),
+        [path] if dump && !trace => dump_file(path),
+        [path] if path == "-" => run_stdin(&mut vm),
+        // A traced run disassembles the chunk before running it, so --dump adds nothing.
+        [path] => run_file(&mut vm, path),
+        // Several scripts run one after another and share their globals.
+        [_, _, ..] if !dump => {
+            let paths: Vec<&str> = args.iter().map(String::as_str).collect();
+            run_files(&mut vm, &paths);
+        },
+        _ => {
+            eprintln!("Usage: clox-rs [--version] [--dump] [--trace] [--json] [path...]");
+            exit(64);
+        }
+    }
 }
@@ -0,0 +1,209 @@
+mod common;
+
+use clox_rs::lox::chunk::{read_header, write_header, ChunkBuilder, OpCode};
+use clox_rs::lox::{compile_only, VERSION};
+use clox_rs::lox::value::Value;
+use clox_rs::lox::vm::InterpretResult;
+use common::TestVm;
+
+#[test]
+fn truncated_chunks_fail_cleanly() {
+    // A `Constant` cut off before its index byte, as a truncated file would give.
+    let chunk = ChunkBuilder::new()
+        .constant(Value::Number(1.0))
+        .op(OpCode::Print)
+        .op(OpCode::Constant)
+        .build();
+    let mut vm = TestVm::new();
+
+    assert!(matches!(vm.vm.run_chunk(chunk), InterpretResult::CompileError));
+    assert_eq!(vm.errors.contents(), "Invalid chunk: OP_CONSTANT at 0003 is missing operand bytes\n");
+    assert_eq!(vm.output.contents(), "");
+}
+
+fn verify_error(builder: ChunkBuilder) -> String {
+    match builder.build().verify() {
+        Ok(()) => panic!("Expected the chunk to be rejected"),
+        Err(msg) => msg,
+    }
+}
+
+#[test]
+fn verify_accepts_compiled_chunks() {
+    let chunk = compile_only(r#"
+        fun greet(name) { return "hi " + name; }
+        var i = 0;
+        while (i < 3) { print greet("x"); i = i + 1; }
+    "#).unwrap_or_else(|_| panic!("Failed to compile"));
+
+    assert_eq!(chunk.verify(), Ok(()));
+}
+
+#[test]
+fn verify_rejects_an_unknown_opcode() {
+    let msg = verify_error(ChunkBuilder::new().op(OpCode::Nil).byte(200));
+
+    assert_eq!(msg, "Unknown opcode 200 at 0001");
+}
+
+#[test]
+fn verify_rejects_a_dangling_operand() {
+    let msg = verify_error(ChunkBuilder::new().op(OpCode::Nil).op(OpCode::Jump).byte(0));
+
+    assert_eq!(msg, "OP_JUMP at 0001 is missing operand bytes");
+}
+
+#[test]
+fn verify_rejects_an_out_of_range_constant() {
+    let msg = verify_error(ChunkBuilder::new().op(OpCode::Constant).byte(5).op(OpCode::Return));
+
+    assert_eq!(msg, "OP_CONSTANT at 0000 uses missing constant 5");
+}
+
+#[test]
+fn verify_rejects_a_missing_string_literal() {
+    let msg = verify_error(ChunkBuilder::new().op(OpCode::StringLiteral).byte(0).op(OpCode::Return));
+
+    assert_eq!(msg, "OP_STRING_LITERAL at 0000 uses missing string literal 0");
+}
+
+#[test]
+fn verify_rejects_a_jump_into_an_operand() {
+    let builder = ChunkBuilder::new()
+        .op(OpCode::Jump).byte(0).byte(1)
+        .constant(Value::Number(1.5))
+        .op(OpCode::Return);
+
+    assert_eq!(verify_error(builder), "OP_JUMP at 0000 targets 0004, which is not an instruction");
+}
+
+#[test]
+fn hand_built_chunks_run() {
+    let chunk = ChunkBuilder::new()
+        .constant(Value::Number(1.0))
+        .constant(Value::Number(2.0))
+        .op(OpCode::Add)
+        .op(OpCode::Print)
+        .op(OpCode::Nil)
+        .op(OpCode::Return)
+        .build();
+    let mut vm = TestVm::new();
+
+    assert!(matches!(vm.vm.run_chunk(chunk), InterpretResult::Ok));
+    assert_eq!(vm.output.contents(), "3\n");
+}
+
+const MULTI_LINE: &str = "fun fail(x) {
+  return x +
+    nil;
+}
+var a = 1;
+
+print fail(
+  a);
+";
+
+#[test]
+fn instructions_map_to_their_lines() {
+    let chunk = compile_only(MULTI_LINE).unwrap_or_else(|_| panic!("Failed to compile"));
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code_size() {
+        let line = chunk.line_at_instruction(offset);
+        if starts.last().is_none_or(|&(_, last)| last != line) {
+            starts.push((offset, line));
+        }
+        offset = chunk.disassemble_instruction(&mut String::new(), offset);
+    }
+
+    assert_eq!(starts, [(0, 1), (5, 2), (7, 3), (10, 4), (16, 5), (22, 7), (26, 8), (33, 9)]);
+    // Operand bytes belong to their instruction's line.
+    assert_eq!(chunk.line_at_instruction(1), 1);
+    assert_eq!(chunk.line_at_instruction(31), 8);
+}
+
+#[test]
+fn runtime_errors_report_the_line_of_each_call() {
+    let mut vm = TestVm::new();
+
+    assert!(matches!(vm.interpret(MULTI_LINE), InterpretResult::RuntimeError(_)));
+    let errors = vm.errors.contents();
+    assert!(errors.starts_with("[line 3] Runtime Error: OP_ADD"));
+    assert!(errors.ends_with("[line 3] in fail()\n[line 8] in script\n"));
+}
+
+#[test]
+fn hand_built_chunks_use_long_global_ids() {
+    let mut builder = ChunkBuilder::new();
+    for i in 0..300 {
+        let name = format!("n{}", i);
+        builder = builder.string(&name).constant(Value::Number(i as f64)).named(OpCode::DefineGlobal, &name);
+    }
+    let chunk = builder
+        .string("n299")
+        .named(OpCode::GetGlobal, "n299")
+        .op(OpCode::Print)
+        .op(OpCode::Nil)
+        .op(OpCode::Return)
+        .build();
+    assert_eq!(chunk.verify(), Ok(()));
+    assert!(chunk.disassemble_to_string("test").contains("OP_GET_GLOBAL_LONG"));
+
+    let mut vm = TestVm::new();
+    assert!(matches!(vm.vm.run_chunk(chunk), InterpretResult::Ok));
+    assert_eq!(vm.output.contents(), "299\n");
+}
+
+#[test]
+fn run_chunk_rekeys_globals_to_the_new_chunk() {
+    let mut vm = TestVm::new();
+    vm.vm.define_global("answer", Value::Number(42.0)).unwrap();
+    vm.run("var scripted = 1;");
+
+    // "x" is literal 0 here, which was a native's id in the VM's previous chunk.
+    let undefined = ChunkBuilder::new()
+        .string("x")
+        .named(OpCode::GetGlobal, "x")
+        .op(OpCode::Print)
+        .op(OpCode::Nil)
+        .op(OpCode::Return)
+        .build();
+    assert_eq!(vm.runtime_error_in(undefined), "Undefined variable 'x'.");
+
+    let chunk = ChunkBuilder::new()
+        .string("len")
+        .named(OpCode::GetGlobal, "len")
+        .string("abc")
+        .op(OpCode::Call)
+        .byte(1)
+        .op(OpCode::Print)
+        .string("answer")
+        .named(OpCode::GetGlobal, "answer")
+        .op(OpCode::Print)
+        .string("scripted")
+        .named(OpCode::GetGlobal, "scripted")
+        .op(OpCode::Print)
+        .op(OpCode::Nil)
+        .op(OpCode::Return)
+        .build();
+    assert_eq!(vm.runtime_error_in(chunk), "Undefined variable 'scripted'.");
+    assert_eq!(vm.output.contents(), "3\n42\n");
+}
+
+#[test]
+fn chunk_headers_carry_the_version() {
+    let mut bytes = Vec::new();
+    write_header(&mut bytes, VERSION);
+    bytes.push(OpCode::Return as u8);
+    assert_eq!(read_header(&bytes, VERSION), Ok(bytes.len() - 1));
+
+    let mut old = Vec::new();
+    write_header(&mut old, "0.0.1");
+    assert_eq!(
+        read_header(&old, "0.2.0"),
+        Err(String::from("Chunk was written by clox-rs 0.0.1, but this is clox-rs 0.2.0"))
+    );
+    assert!(read_header(&old, VERSION).is_err());
+    assert_eq!(read_header(&old[..6], "0.0.1"), Err(String::from("Chunk header is truncated")));
+    assert_eq!(read_header(b"print 1;", VERSION), Err(String::from("Not a serialized chunk")));
+}
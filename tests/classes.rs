@@ -0,0 +1,110 @@
+mod common;
+
+use clox_rs::lox::compile_only;
+use common::{run, TestVm};
+
+const POINT: &str = r#"
+    class Point {
+        init(x, y) {
+            this.x = x;
+            this.y = y;
+        }
+        sum() { return this.x + this.y; }
+        scale(k) { this.x = this.x * k; this.y = this.y * k; return this; }
+    }
+"#;
+
+#[test]
+fn init_sets_fields_and_methods_use_this() {
+    let source = format!("{}{}", POINT, r#"
+        var p = Point(1, 2);
+        print p.x;
+        print p.sum();
+        print p.scale(3).sum();
+        print p;
+        print Point;
+    "#);
+
+    assert_eq!(run(&source), "1\n3\n9\nPoint instance\nPoint\n");
+}
+
+#[test]
+fn fields_can_be_added_to_any_instance() {
+    let output = run(r#"
+        class Empty {}
+        var e = Empty();
+        e.field = "value";
+        print e.field;
+        e.f = fun (a) { return a * 10; };
+        print e.f(4);
+    "#);
+
+    assert_eq!(output, "value\n40\n");
+}
+
+#[test]
+fn bound_methods_keep_their_receiver() {
+    let source = format!("{}{}", POINT, r#"
+        var p = Point(1, 2);
+        var sum = p.sum;
+        p.x = 10;
+        print sum();
+        print sum;
+
+        class Counter {
+            init() { this.n = 0; }
+            inc() { var f = fun () { this.n = this.n + 1; }; f(); return this.n; }
+        }
+        var c = Counter();
+        var inc = c.inc;
+        inc();
+        print c.inc();
+    "#);
+
+    assert_eq!(run(&source), "12\n<fn sum>\n2\n");
+}
+
+#[test]
+fn init_checks_the_argument_count() {
+    let mut vm = TestVm::new();
+    vm.run(POINT);
+
+    assert_eq!(vm.runtime_error("Point(1);"), "Expected 2 arguments but got 1.");
+    assert_eq!(vm.runtime_error("class Empty {} Empty(1);"), "Expected 0 arguments but got 1.");
+}
+
+#[test]
+fn overridden_methods_can_call_super() {
+    let output = run(r#"
+        class Base {
+            init(name) { this.name = name; }
+            describe() { return "base " + this.name; }
+        }
+        class Derived < Base {
+            init(name) { super.init(name + "!"); }
+            describe() { return "derived, " + super.describe(); }
+        }
+        class Leaf < Derived {}
+
+        var d = Derived("d");
+        print d.describe();
+        var method = d.describe;
+        print method();
+        print Leaf("leaf").describe();
+    "#);
+
+    assert_eq!(output, "derived, base d!\nderived, base d!\nderived, base leaf!\n");
+}
+
+#[test]
+fn classes_inherit_only_from_other_classes() {
+    let mut vm = TestVm::new();
+    assert_eq!(vm.runtime_error(r#"var NotAClass = "x"; class A < NotAClass {}"#), "Superclass must be a class.");
+
+    let errors = match compile_only("class A < A {}") {
+        Ok(_) => panic!("Expected a compile error"),
+        Err(errors) => errors,
+    };
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "A class can't inherit from itself.");
+}
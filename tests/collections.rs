@@ -0,0 +1,65 @@
+mod common;
+
+use common::{run, TestVm};
+
+#[test]
+fn maps_are_built_read_and_updated_by_key() {
+    let output = run(r#"
+        var m = {"a": 1, "b": 2};
+        print m["a"];
+        m["a"] = 10;
+        m["c"] = 3;
+        print m;
+        m[1] = "one";
+        m[true] = "yes";
+        m[nil] = "nothing";
+        print m[1], m[true], m[nil], m["a" + ""];
+        print {};
+    "#);
+
+    assert_eq!(output, "1\n{\"a\": 10, \"b\": 2, \"c\": 3}\none yes nothing 10\n{}\n");
+}
+
+#[test]
+fn maps_reject_missing_and_unhashable_keys() {
+    let mut vm = TestVm::new();
+    vm.run(r#"var m = {"a": 1};"#);
+
+    // Reading a missing key is an error rather than nil, so typos don't go unnoticed.
+    assert_eq!(vm.runtime_error(r#"print m["zzz"];"#), "Undefined key \"zzz\".");
+    assert_eq!(vm.runtime_error("m[0 / 0] = 1;"), "NaN can't be a map key.");
+    assert_eq!(vm.runtime_error("print m[[1]];"), "Map keys must be nil, bools, numbers or strings, got list.");
+    assert_eq!(vm.runtime_error(r#"print 1["a"];"#), "Can only index lists and maps, got number.");
+}
+
+#[test]
+fn lists_are_built_read_and_written_by_index() {
+    let output = run(r#"
+        var xs = [1, 2, 3];
+        print xs;
+        print xs[0], xs[2], xs[-1];
+        xs[1] = "two";
+        xs[-1] = xs[0] + 10;
+        print xs;
+        print len(xs), len([]);
+        var nested = [[1, 2], [3]];
+        nested[0][1] = 20;
+        print nested;
+    "#);
+
+    assert_eq!(output, "[1, 2, 3]\n1 3 3\n[1, \"two\", 11]\n3 0\n[[1, 20], [3]]\n");
+}
+
+#[test]
+fn list_indices_are_bounds_checked() {
+    let mut vm = TestVm::new();
+    vm.run("var xs = [1, 2, 3];");
+
+    assert_eq!(vm.runtime_error("print xs[3];"), "List index 3 is out of range for a list of length 3.");
+    // Negative indices count from the end, but only as far back as the first element.
+    assert_eq!(vm.run("print xs[-3];"), "1\n");
+    assert_eq!(vm.runtime_error("print xs[-4];"), "List index -4 is out of range for a list of length 3.");
+    assert_eq!(vm.runtime_error("xs[5] = 1;"), "List index 5 is out of range for a list of length 3.");
+    assert_eq!(vm.runtime_error("print xs[0.5];"), "List index must be a whole number, got 0.5.");
+    assert_eq!(vm.runtime_error(r#"print xs["a"];"#), "List index must be a number, got string.");
+}
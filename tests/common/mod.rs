@@ -0,0 +1,88 @@
+// Each test binary uses its own subset of these helpers.
+#![allow(dead_code)]
+
+use clox_rs::lox::chunk::Chunk;
+use clox_rs::lox::vm::{InterpretResult, VM};
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// A `Write` sink whose contents can still be read after handing it to the VM.
+#[derive(Clone, Default)]
+pub struct Sink(Rc<RefCell<Vec<u8>>>);
+
+impl Sink {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).expect("Sink holds invalid UTF-8")
+    }
+
+    /// Forgets everything written so far.
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A VM writing its output and errors to sinks the test can read.
+pub struct TestVm {
+    pub vm: VM,
+    pub output: Sink,
+    pub errors: Sink,
+}
+
+impl TestVm {
+    pub fn new() -> TestVm {
+        let output = Sink::default();
+        let errors = Sink::default();
+        let mut vm = VM::new(false);
+        vm.set_output(Box::new(output.clone()));
+        vm.set_errors(Box::new(errors.clone()));
+
+        TestVm { vm, output, errors }
+    }
+
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        self.vm.interpret(source)
+    }
+
+    /// Runs `source`, expecting it to succeed, and returns what it printed.
+    pub fn run(&mut self, source: &str) -> String {
+        self.output.clear();
+        match self.interpret(source) {
+            InterpretResult::Ok => self.output.contents(),
+            _ => panic!("Script failed:\n{}", self.errors.contents()),
+        }
+    }
+
+    /// Runs `chunk`, expecting a runtime error, and returns its message.
+    pub fn runtime_error_in(&mut self, chunk: Chunk) -> String {
+        match self.vm.run_chunk(chunk) {
+            InterpretResult::RuntimeError(error) => error.message,
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+
+    /// Runs `source`, expecting a runtime error, and returns its message.
+    pub fn runtime_error(&mut self, source: &str) -> String {
+        match self.interpret(source) {
+            InterpretResult::RuntimeError(error) => error.message,
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+}
+
+/// Runs `source` in a fresh VM and returns what it printed.
+pub fn run(source: &str) -> String {
+    TestVm::new().run(source)
+}
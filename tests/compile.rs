@@ -0,0 +1,102 @@
+use clox_rs::lox::chunk::OpCode;
+use clox_rs::lox::compile_only;
+use clox_rs::lox::compiler::{compile_with, CompileOptions};
+use clox_rs::lox::value::Value;
+
+#[test]
+fn compile_only_returns_the_chunk_without_running_it() {
+    let chunk = compile_only("print 1 + 2;").unwrap_or_else(|_| panic!("Failed to compile"));
+
+    assert_eq!(OpCode::from_u8(chunk.byte(0)), OpCode::Int);
+    assert_eq!(chunk.byte(1), 1);
+    assert_eq!(OpCode::from_u8(chunk.byte(2)), OpCode::Int);
+    assert_eq!(chunk.byte(3), 2);
+    assert_eq!(OpCode::from_u8(chunk.byte(4)), OpCode::Add);
+    assert_eq!(OpCode::from_u8(chunk.byte(5)), OpCode::Print);
+    assert_eq!(OpCode::from_u8(chunk.byte(chunk.code_size() - 1)), OpCode::Return);
+    assert!(chunk.verify().is_ok());
+}
+
+#[test]
+fn compile_only_reports_every_error() {
+    let errors = match compile_only("print ;\nvar = 1;") {
+        Ok(_) => panic!("Expected compile errors"),
+        Err(errors) => errors,
+    };
+
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].line, 1);
+    assert_eq!(errors[0].message, "Expect expression.");
+    assert_eq!(errors[1].line, 2);
+    assert_eq!(errors[1].message, "Expect variable name.");
+}
+
+/// The compile errors for `source`, formatted as they're reported.
+fn compile_errors(source: &str) -> Vec<String> {
+    match compile_only(source) {
+        Ok(_) => panic!("Expected compile errors"),
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+#[test]
+fn line_directives_set_the_reported_line() {
+    assert_eq!(
+        compile_errors("print 1;\n#line 42\nprint ;\nprint ;\n"),
+        ["[line 42] Error: Expect expression.", "[line 43] Error: Expect expression."]
+    );
+
+    let chunk = compile_only("#line 10\nprint 1;\n#line 3\nprint 2;").unwrap_or_else(|_| panic!("Failed to compile"));
+    assert_eq!(chunk.line_at_instruction(0), 10);
+    assert_eq!(chunk.line_at_instruction(chunk.code_size() - 1), 3);
+}
+
+#[test]
+fn malformed_line_directives_are_errors() {
+    assert_eq!(
+        // Two statements apart, so each is scanned after the parser recovered from the last one.
+        compile_errors("#line abc\nprint 1;\nprint 2;\n#line\nprint 3;\nprint 4;\n#lines 5\nprint 5;\nprint 6;\n#line 0\nprint 7;"),
+        [
+            "[line 1] Error: Malformed line directive. Expect '#line N'.",
+            "[line 4] Error: Malformed line directive. Expect '#line N'.",
+            "[line 7] Error: Malformed line directive. Expect '#line N'.",
+            "[line 10] Error: Line number in directive must be between 1 and 4294967295.",
+        ]
+    );
+}
+
+#[test]
+fn strict_conditions_reject_assignments_as_conditions() {
+    let strict = CompileOptions { strict_conditions: true, ..CompileOptions::default() };
+    let errors = |source: &str| match compile_with(source, strict) {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    };
+
+    let message = "Assignment used as a condition; did you mean '=='?";
+    assert_eq!(errors("var x = 0;\nif (x = 1) print x;"), [format!("[line 2] Error: {}", message)]);
+    assert_eq!(errors("var x = 0;\nwhile (x = nil) {}"), [format!("[line 2] Error: {}", message)]);
+    // Comparisons, and assignments in extra parentheses, are fine.
+    assert!(errors("var x = 0;\nif (x == 1) print x;\nif ((x = 1)) print x;").is_empty());
+    assert!(errors("var x = 0;\nif ((x = 1) and x) print x;").is_empty());
+
+    assert!(compile_only("var x = 0;\nif (x = 1) print x;\nwhile (x = nil) {}").is_ok());
+}
+
+#[test]
+fn small_integers_are_encoded_inline() {
+    let mut chunk = compile_only("print 0; print 1; print 255;").unwrap_or_else(|_| panic!("Failed to compile"));
+    let disassembly = chunk.disassemble_to_string("ints");
+    assert!(disassembly.contains("0000    1 OP_INT              0\n"), "{}", disassembly);
+    assert!(disassembly.contains("0003    | OP_INT              1\n"), "{}", disassembly);
+    assert!(disassembly.contains("0006    | OP_INT            255\n"), "{}", disassembly);
+    // Nothing went into the constant pool, so the next constant takes the first slot.
+    assert_eq!(chunk.add_constant(Value::Nil), Ok(0));
+
+    // Anything else still needs a constant.
+    let mut chunk = compile_only("print 256; print 1.5; print 1e3;").unwrap_or_else(|_| panic!("Failed to compile"));
+    let disassembly = chunk.disassemble_to_string("constants");
+    assert_eq!(disassembly.matches("OP_CONSTANT").count(), 3, "{}", disassembly);
+    assert!(!disassembly.contains("OP_INT"), "{}", disassembly);
+    assert_eq!(chunk.add_constant(Value::Nil), Ok(3));
+}
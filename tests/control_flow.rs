@@ -0,0 +1,215 @@
+mod common;
+
+use clox_rs::lox::compile_only;
+use common::{run, TestVm};
+
+/// Opcode names of the instructions compiled from `source`, in order.
+fn opcodes(source: &str) -> Vec<String> {
+    let chunk = compile_only(source).unwrap_or_else(|_| panic!("Failed to compile"));
+
+    chunk.disassemble_to_string("test")
+        .lines()
+        .skip(1)
+        .map(|line| {
+            // Lines look like `0003    2 OP_NIL` or, on a repeated line, `0004    | OP_POP`.
+            let mut parts = line.split_whitespace().skip(2);
+            parts.next().unwrap().to_string()
+        })
+        .collect()
+}
+
+const LADDER: &str = r#"
+    if (x == 1) print "one";
+    else if (x == 2) print "two";
+    else if (x == 3) print "three";
+    else if (x == 4) print "four";
+    else print "other";
+"#;
+
+#[test]
+fn else_if_ladder_picks_the_first_true_branch() {
+    let source = format!("for (x in [1, 2, 3, 4, 5, 6]) {{ {} }}", LADDER);
+
+    assert_eq!(run(&source), "one\ntwo\nthree\nfour\nother\nother\n");
+}
+
+#[test]
+fn else_if_ladder_compiles_flat() {
+    let source = format!("var x = 3;\n{}", LADDER);
+    let ops = opcodes(&source);
+    let control = ops.iter().filter(|op| op.starts_with("OP_JUMP") || op.starts_with("OP_POP")).count();
+
+    // Nesting emits a JumpIfFalse, a Pop, a Jump and another Pop for each of the four conditions.
+    let nested = ops.len() - control + 4 * 4;
+    // The flat chain only needs a popping jump per condition and a jump to the end per branch.
+    assert_eq!(control, 4 + 4);
+    assert!(ops.len() < nested);
+    assert_eq!(run(&source), "three\n");
+}
+
+#[test]
+fn if_without_else_pops_its_condition() {
+    let output = run(r#"
+        var hits = 0;
+        for (i in [1, 2, 3, 4]) {
+            if (i > 2) hits = hits + 1;
+        }
+        print hits;
+        if (false) print "no"; else if (nil) print "no";
+        print "done";
+    "#);
+
+    assert_eq!(output, "2\ndone\n");
+}
+
+#[test]
+fn block_expression_evaluates_to_its_trailing_expression() {
+    let output = run(r#"
+        print { var a = 1; var b = 2; a + b };
+        print { var a = 1; };
+        print 10 + { var a = 2; { var b = 3; a * b } } * 2;
+    "#);
+
+    assert_eq!(output, "3\nnil\n22\n");
+}
+
+#[test]
+fn block_expression_keeps_the_enclosing_stack() {
+    let output = run(r#"
+        fun add(a, b, c) { return a + b + c; }
+        fun f(x) {
+            var y = x + { var z = x * 2; z + 1 };
+            print add(x, { var w = y; w - 1 }, y);
+            return y;
+        }
+        print f(3);
+        {
+            var outer = "o";
+            var inner = { var s = outer + "i"; s + "!" };
+            print outer + inner;
+        }
+    "#);
+
+    assert_eq!(output, "22\n10\nooi!\n");
+}
+
+#[test]
+fn block_expression_reaches_the_enclosing_loop_and_function() {
+    let output = run(r#"
+        for (i in [1, 2, 3, 4]) {
+            if (i == 2) continue;
+            print 100 + { var j = i; if (j == 4) break; j };
+        }
+        fun first(list) {
+            for (x in list) {
+                var found = { var y = x; if (y > 1) return y; nil };
+            }
+            return "none";
+        }
+        print first([0, 1, 5, 7]);
+        print first([]);
+    "#);
+
+    assert_eq!(output, "101\n103\n5\nnone\n");
+}
+
+#[test]
+fn block_expression_locals_can_be_captured() {
+    let output = run(r#"
+        var getters = [nil, nil, nil];
+        for (i in [0, 1, 2]) {
+            getters[i] = { var n = i * 10; fun get() { return n; } get };
+        }
+        for (get in getters) print get();
+    "#);
+
+    assert_eq!(output, "0\n10\n20\n");
+}
+
+#[test]
+fn brace_with_a_name_and_colon_is_a_map_unless_a_loop_follows() {
+    let output = run(r#"
+        var k = "a";
+        var m = {k: 1, "b": 2, 3: "c", true: 4};
+        print m["a"] + m["b"] + m[true];
+        print m[3];
+        var r = {
+            outer: for (x in [1, 2]) {
+                for (y in [1, 2]) {
+                    print x * 10 + y;
+                    continue outer;
+                }
+            }
+            "done"
+        };
+        print r;
+    "#);
+
+    assert_eq!(output, "7\nc\n11\n21\ndone\n");
+}
+
+#[test]
+fn loops_in_a_labeled_loops_header_leave_its_label() {
+    let output = run(r#"
+        var seen = 0;
+        outer: for (x in { var list = [1, 2]; while (false) {} list }) {
+            for (y in [1, 2]) {
+                seen = seen + 1;
+                continue outer;
+            }
+        }
+        print seen;
+        outer: while ((fun () { for (i in []) {} return true; })()) {
+            while (true) break outer;
+        }
+        print "out";
+    "#);
+
+    assert_eq!(output, "2\nout\n");
+}
+
+#[test]
+fn for_in_walks_list_items_and_map_keys() {
+    let output = run(r#"
+        var sum = 0;
+        for (x in [1, 2, 3, 4]) sum = sum + x;
+        print sum;
+
+        var m = {"b": 1, "a": 2, "c": 3};
+        var total = 0;
+        for (key in m) {
+            print key;
+            total = total + m[key];
+        }
+        print total;
+
+        for (x in []) print "never";
+        for (x in {}) print "never";
+    "#);
+
+    // Map keys come out sorted, not in insertion order.
+    assert_eq!(output, "10\na\nb\nc\n6\n");
+
+    let mut vm = TestVm::new();
+    assert_eq!(vm.runtime_error("for (x in 5) print x;"), "Can only iterate over lists and maps, got number.");
+}
+
+#[test]
+fn do_while_runs_its_body_before_checking_the_condition() {
+    let output = run(r#"
+        do { print "once"; } while (false);
+        var n = 0;
+        do { n = n + 1; } while (n < 5);
+        print n;
+        do print n--; while (n > 3);
+        var runs = 0;
+        do {
+            runs = runs + 1;
+            if (runs == 3) break;
+            continue;
+        } while (true);
+        print runs;
+    "#);
+
+    assert_eq!(output, "once\n5\n5\n4\n3\n");
+}
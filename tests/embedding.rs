@@ -0,0 +1,67 @@
+mod common;
+
+use clox_rs::lox::exit_code;
+use clox_rs::lox::value::Value;
+use clox_rs::lox::vm::{InterpretResult, TraceOptions};
+use common::TestVm;
+
+#[test]
+fn instruction_budget_interrupts_an_infinite_loop() {
+    let mut vm = TestVm::new();
+    vm.vm.instruction_budget = Some(1000);
+
+    assert!(matches!(vm.interpret("while (true) {}"), InterpretResult::Interrupted));
+    assert_eq!(vm.vm.instruction_budget, Some(0));
+
+    // The interrupted run is cleaned up, so the VM can run again with a fresh budget.
+    vm.vm.instruction_budget = Some(1000);
+    assert_eq!(vm.run("print 1;"), "1\n");
+}
+
+#[test]
+fn host_defined_globals_are_visible_to_scripts() {
+    let mut vm = TestVm::new();
+    vm.vm.define_global("version", Value::Number(3.0)).unwrap();
+
+    assert_eq!(vm.run("print version;"), "3\n");
+    assert_eq!(vm.run("version = version + 1; print version;"), "4\n");
+}
+
+#[test]
+fn reset_keeps_only_what_the_host_defined() {
+    let mut vm = TestVm::new();
+    vm.vm.define_global("version", Value::Number(3.0)).unwrap();
+    vm.run("var f = len; var mine = 1; version = 4; len = nil;");
+
+    vm.vm.reset();
+    assert_eq!(vm.runtime_error("print f;"), "Undefined variable 'f'.");
+    assert_eq!(vm.runtime_error("print mine;"), "Undefined variable 'mine'.");
+    assert_eq!(vm.run(r#"print version; print len("abc");"#), "3\n3\n");
+}
+
+#[test]
+fn trace_options_toggle_trace_output() {
+    let mut vm = TestVm::new();
+    assert_eq!(vm.run("print 1;"), "1\n");
+
+    vm.vm.trace = TraceOptions { disassemble: true, instructions: true };
+    let output = vm.run("print 2;");
+    assert!(output.starts_with("== script ==\n"));
+    assert!(output.contains("\nStack\n"));
+    // One trace per executed instruction: Int, Print, Nil and Return.
+    assert_eq!(output.matches("\nInstruction\n").count(), 4);
+    assert!(output.contains("2\n"));
+
+    vm.vm.trace = TraceOptions::default();
+    assert_eq!(vm.run("print 3;"), "3\n");
+}
+
+#[test]
+fn results_map_to_exit_codes() {
+    let mut vm = TestVm::new();
+
+    assert_eq!(exit_code(&vm.interpret("print 1;")), 0);
+    assert_eq!(exit_code(&vm.interpret("print ;")), 65);
+    assert_eq!(exit_code(&vm.interpret("print nil + 1;")), 70);
+    assert_eq!(exit_code(&InterpretResult::Interrupted), 70);
+}
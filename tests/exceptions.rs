@@ -0,0 +1,113 @@
+mod common;
+
+use clox_rs::lox::vm::InterpretResult;
+use common::{run, TestVm};
+
+#[test]
+fn catch_receives_a_thrown_value() {
+    let output = run(r#"
+        try {
+            print "before";
+            throw "boom";
+            print "skipped";
+        } catch (e) {
+            print "caught " + e;
+        }
+        print "after";
+    "#);
+
+    assert_eq!(output, "before\ncaught boom\nafter\n");
+}
+
+#[test]
+fn catch_receives_a_runtime_error() {
+    let output = run(r#"
+        var kept = "kept";
+        try {
+            var local = 1;
+            print nil + local;
+        } catch (e) {
+            print kept;
+            print e;
+        }
+    "#);
+
+    assert_eq!(output, "kept\nOperands must be two numbers or two strings, got nil and number.\n");
+}
+
+#[test]
+fn errors_unwind_to_the_callers_catch() {
+    let output = run(r#"
+        fun inner(x) { var y = x; throw "from inner " + y; }
+        fun outer() { return inner("a") + 1; }
+        fun counter() {
+            var count = 0;
+            try {
+                fun bump() { count = count + 1; throw "stop"; }
+                bump();
+            } catch (e) {
+                print e;
+            }
+            return count;
+        }
+        try {
+            outer();
+        } catch (e) {
+            print e;
+        }
+        print counter();
+    "#);
+
+    assert_eq!(output, "from inner a\nstop\n1\n");
+}
+
+#[test]
+fn uncaught_errors_still_fail_the_script() {
+    let mut vm = TestVm::new();
+
+    assert_eq!(vm.runtime_error(r#"throw "loose";"#), "loose");
+    // A catch block's own error isn't caught by the try it belongs to.
+    assert_eq!(vm.runtime_error(r#"try { throw "first"; } catch (e) { throw "second"; }"#), "second");
+}
+
+#[test]
+fn returning_from_a_try_block_drops_its_handler() {
+    let mut vm = TestVm::new();
+    vm.run(r#"
+        fun guarded() {
+            try {
+                return "returned";
+            } catch (e) {
+                print "should not catch";
+            }
+        }
+    "#);
+
+    assert_eq!(vm.run("print guarded();"), "returned\n");
+    vm.output.clear();
+    assert_eq!(vm.runtime_error(r#"guarded(); throw "outside";"#), "outside");
+    assert_eq!(vm.output.contents(), "");
+}
+
+#[test]
+fn throw_reports_its_message_and_line() {
+    let mut vm = TestVm::new();
+    let error = match vm.interpret("print 1;\n\nthrow \"boom\";\nprint 2;") {
+        InterpretResult::RuntimeError(error) => error,
+        _ => panic!("Expected a runtime error"),
+    };
+
+    assert_eq!(error.message, "boom");
+    assert_eq!(error.line, 3);
+    assert!(vm.errors.contents().starts_with("[line 3] Runtime Error: OP_THROW boom\n"));
+    assert_eq!(vm.output.contents(), "1\n");
+}
+
+#[test]
+fn thrown_numbers_are_stringified() {
+    let mut vm = TestVm::new();
+
+    assert_eq!(vm.runtime_error("throw 42;"), "42");
+    assert_eq!(vm.runtime_error("throw 1.5 * 2;"), "3");
+    assert_eq!(vm.run("try { throw 7; } catch (e) { print e + \"!\"; }"), "7!\n");
+}
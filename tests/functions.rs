@@ -0,0 +1,132 @@
+mod common;
+
+use clox_rs::lox::vm::InterpretResult;
+use common::{run, TestVm};
+
+#[test]
+fn closure_counters_count_independently() {
+    let output = run(r#"
+        fun makeCounter() {
+            var count = 0;
+            fun counter() {
+                count = count + 1;
+                return count;
+            }
+            return counter;
+        }
+
+        var a = makeCounter();
+        var b = makeCounter();
+        print a();
+        print a();
+        print b();
+        print a();
+    "#);
+
+    assert_eq!(output, "1\n2\n1\n3\n");
+}
+
+#[test]
+fn closures_share_a_captured_variable() {
+    let output = run(r#"
+        var get;
+        var set;
+        fun make() {
+            var shared = "initial";
+            fun getter() { return shared; }
+            fun setter(value) { shared = value; }
+            get = getter;
+            set = setter;
+        }
+
+        make();
+        print get();
+        set("updated");
+        print get();
+    "#);
+
+    assert_eq!(output, "initial\nupdated\n");
+}
+
+#[test]
+fn calls_with_the_wrong_number_of_arguments_fail() {
+    let mut vm = TestVm::new();
+    vm.run("fun add(a, b) { return a + b; }");
+
+    assert_eq!(vm.runtime_error("add(1);"), "Expected 2 arguments but got 1.");
+    assert_eq!(vm.runtime_error("print add(1, 2, 3);"), "Expected 2 arguments but got 3.");
+    // The failed calls leave nothing behind on the stack.
+    assert_eq!(vm.run("print add(1, 2);"), "3\n");
+}
+
+#[test]
+fn lambdas_are_values() {
+    let output = run(r#"
+        var g = fun (x) { return x + 1; };
+        print g(1);
+        print g;
+        print (fun (a, b) { return a * b; })(6, 7);
+
+        fun apply(f, x) { return f(x); }
+        print apply(fun (x) { return x * 10; }, 4);
+    "#);
+
+    assert_eq!(output, "2\n<fn>\n42\n40\n");
+}
+
+#[test]
+fn lambdas_capture_enclosing_locals() {
+    let output = run(r#"
+        fun adder(n) {
+            return fun (x) { return x + n; };
+        }
+        var add5 = adder(5);
+        print add5(1);
+
+        fun counter() {
+            var count = 0;
+            return fun () { count = count + 1; return count; };
+        }
+        var next = counter();
+        next();
+        print next();
+    "#);
+
+    assert_eq!(output, "6\n2\n");
+}
+
+#[test]
+fn runtime_errors_trace_every_call_innermost_first() {
+    let mut vm = TestVm::new();
+    let source = "fun inner() {\n  return nil + 1;\n}\nfun outer() {\n  return inner();\n}\n\nouter();\n";
+    let error = match vm.interpret(source) {
+        InterpretResult::RuntimeError(error) => error,
+        _ => panic!("Expected a runtime error"),
+    };
+
+    assert_eq!(error.line, 2);
+    assert_eq!(error.trace, ["[line 2] in inner()", "[line 5] in outer()", "[line 8] in script"]);
+    assert!(vm.errors.contents().ends_with("[line 2] in inner()\n[line 5] in outer()\n[line 8] in script\n"));
+}
+
+#[test]
+fn returned_lists_destructure_into_variables() {
+    let output = run(r#"
+        fun divmod(a, b) { var q = floor(a / b); return [q, a - q * b]; }
+        var (q, r) = divmod(7, 3);
+        print q, r;
+        fun sum3() { var (x, y, z) = [1, 2, 3]; return x + y + z; }
+        print sum3();
+    "#);
+
+    assert_eq!(output, "2 1\n6\n");
+}
+
+#[test]
+fn destructuring_needs_exactly_one_value_per_name() {
+    let mut vm = TestVm::new();
+
+    assert_eq!(vm.runtime_error("var (a, b) = [1];"), "Expected 2 values to unpack, got 1.");
+    assert_eq!(vm.runtime_error("var (a, b) = [1, 2, 3];"), "Expected 2 values to unpack, got 3.");
+    assert_eq!(vm.runtime_error("var (a, b) = 5;"), "Only lists can be unpacked, got number.");
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use clox_rs::lox::compile_only;
+use common::TestVm;
+
+#[test]
+fn three_hundred_distinct_globals() {
+    let mut source = String::new();
+    for i in 0..300 {
+        source += &format!("var g{} = {};\n", i, i);
+    }
+    source += "var sum = 0;\n";
+    for i in 0..300 {
+        source += &format!("sum = sum + g{};\n", i);
+    }
+    source += "print sum;\ng299 = -1;\nprint g299;\ndel g299;\n";
+
+    let disassembly = compile_only(&source).unwrap_or_else(|_| panic!("Failed to compile")).disassemble_to_string("test");
+    for opcode in ["OP_DEFINE_GLOBAL_LONG", "OP_GET_GLOBAL_LONG", "OP_SET_GLOBAL_LONG", "OP_DELETE_GLOBAL_LONG"] {
+        assert!(disassembly.contains(opcode), "{} not used", opcode);
+    }
+
+    let mut vm = TestVm::new();
+    assert_eq!(vm.run(&source), "44850\n-1\n");
+    assert_eq!(vm.runtime_error("print g299;"), "Undefined variable 'g299'.");
+    assert_eq!(vm.run("print g298;"), "298\n");
+}
+
+#[test]
+fn dump_globals_lists_globals_sorted_by_name() {
+    let mut vm = TestVm::new();
+    vm.run(r#"
+        var zeta = "last";
+        var alpha = 1;
+        var Beta = nil;
+        var list = [1, "two"];
+        fun mid() {}
+    "#);
+
+    // Natives are globals too. Uppercase sorts before lowercase.
+    assert_eq!(vm.vm.dump_globals(), "\
+Beta = nil
+abs = <native fn>
+alpha = 1
+ceil = <native fn>
+clone = <native fn>
+floor = <native fn>
+fmt = <native fn>
+len = <native fn>
+list = [1, \"two\"]
+log = <native fn>
+max = <native fn>
+mid = <fn mid>
+min = <native fn>
+parseNumber = <native fn>
+readLine = <native fn>
+round = <native fn>
+sqrt = <native fn>
+substr = <native fn>
+zeta = last
+");
+}
+
+#[test]
+fn del_removes_a_global_binding() {
+    let mut vm = TestVm::new();
+    assert_eq!(vm.run("var a = 1; print a; del a; var a = 2; print a;"), "1\n2\n");
+
+    vm.run("del a;");
+    assert_eq!(vm.runtime_error("print a;"), "Undefined variable 'a'.");
+    assert_eq!(vm.runtime_error("del a;"), "Undefined variable 'a'.");
+    assert_eq!(vm.runtime_error("a = 3;"), "Undefined variable 'a'.");
+}
+
+#[test]
+fn del_only_takes_a_global_name() {
+    for (source, message) in [
+        ("fun f() { var x = 1; del x; }", "Can only delete global variables."),
+        ("del 1;", "Expect variable name after 'del'."),
+    ] {
+        let errors = match compile_only(source) {
+            Ok(_) => panic!("Expected a compile error for {}", source),
+            Err(errors) => errors,
+        };
+        assert_eq!(errors[0].message, message);
+    }
+}
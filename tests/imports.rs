@@ -0,0 +1,76 @@
+mod common;
+
+use clox_rs::lox::vm::InterpretResult;
+use common::TestVm;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A fresh directory for one test's files, so tests running in parallel don't share any.
+fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("clox-rs-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes `source` to `name` in `dir` and returns its path, for use in an `import`.
+fn write_file(dir: &Path, name: &str, source: &str) -> String {
+    let path = dir.join(name);
+    fs::write(&path, source).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// Runs the file at `path` and returns its compile errors.
+fn compile_errors(vm: &mut TestVm, path: &str) -> String {
+    let source = fs::read_to_string(path).unwrap();
+    assert!(matches!(vm.vm.interpret_file(&source, path), InterpretResult::CompileError));
+    vm.errors.contents()
+}
+
+#[test]
+fn imported_declarations_are_compiled_in_place() {
+    let dir = scratch_dir("include");
+    let lib = write_file(&dir, "lib.lox", "fun double(x) { return x * 2; }\nvar greeting = \"hi\";\n");
+    let mut vm = TestVm::new();
+
+    assert_eq!(vm.run(&format!("import \"{}\";\nprint double(21);\nprint greeting;", lib)), "42\nhi\n");
+}
+
+#[test]
+fn importing_a_missing_file_names_the_path() {
+    let dir = scratch_dir("missing");
+    let missing = dir.join("missing.lox");
+    let mut vm = TestVm::new();
+
+    assert!(matches!(vm.interpret(&format!("import \"{}\";", missing.display())), InterpretResult::CompileError));
+    let errors = vm.errors.contents();
+    assert!(errors.contains(&format!("Can't import '{}'", missing.display())), "{}", errors);
+}
+
+#[test]
+fn a_file_importing_itself_is_a_cycle() {
+    let dir = scratch_dir("self-import");
+    let path = dir.join("self.lox");
+    let path = write_file(&dir, "self.lox", &format!("print \"once\";\nimport \"{}\";\n", path.display()));
+    let mut vm = TestVm::new();
+
+    let errors = compile_errors(&mut vm, &path);
+    assert_eq!(errors.matches("Import cycle").count(), 1, "{}", errors);
+    assert!(errors.starts_with("[line 2] Error at "), "{}", errors);
+    // The cycle is caught before the file is compiled a second time.
+    assert_eq!(vm.vm.chunk().disassemble_to_string("self").matches("OP_PRINT").count(), 1);
+}
+
+#[test]
+fn two_files_importing_each_other_are_a_cycle() {
+    let dir = scratch_dir("two-file-cycle");
+    let a = dir.join("a.lox");
+    let b = write_file(&dir, "b.lox", &format!("import \"{}\";\n", a.display()));
+    let a = write_file(&dir, "a.lox", &format!("import \"{}\";\n", b));
+    let mut vm = TestVm::new();
+
+    let errors = compile_errors(&mut vm, &a);
+    assert_eq!(errors.matches("Import cycle").count(), 1, "{}", errors);
+    assert!(errors.contains(&format!("Import cycle: '{}' is already being imported.", a)), "{}", errors);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::{run, TestVm};
+
+#[test]
+fn math_natives_round_and_compare_numbers() {
+    let output = run(r#"
+        print floor(2.7), floor(-2.7), ceil(2.1), ceil(-2.1);
+        print round(2.5), round(-2.5), round(2.4), round(-0.4);
+        print abs(-3), abs(3), abs(-0.5);
+        print sqrt(16), sqrt(2), sqrt(-1);
+        print min(1, 2), min(-1, -2), max(1, 2), max(-1, -2);
+        print min([3, 1, 2]), max([3, 1, 2]);
+    "#);
+
+    assert_eq!(output, "\
+2 -3 3 -2
+3 -3 2 -0
+3 3 0.5
+4 1.4142135623730951 nan
+1 -2 2 -1
+1 3
+");
+}
+
+#[test]
+fn math_natives_only_take_numbers() {
+    let mut vm = TestVm::new();
+
+    assert_eq!(vm.runtime_error(r#"floor("a");"#), "floor() expects a number, got string.");
+    assert_eq!(vm.runtime_error("abs(nil);"), "abs() expects a number, got nil.");
+    assert_eq!(vm.runtime_error(r#"min(1, "a");"#), "min() expects a number, got string.");
+    assert_eq!(vm.runtime_error("max(1);"), "max() expects a list or two numbers, got number.");
+    assert_eq!(vm.runtime_error("min([]);"), "min() expects a non-empty list.");
+}
+
+#[test]
+fn fmt_fills_placeholders_with_printed_values() {
+    let output = run(r#"
+        var a = 1;
+        var b = 2;
+        print fmt("{} + {} = {}", a, b, a + b);
+        print fmt("{{}} {} {{x}}", "v");
+        print fmt("none");
+        print fmt("{}|{}|{}", nil, [1, "s"], true);
+    "#);
+
+    assert_eq!(output, "1 + 2 = 3\n{} v {x}\nnone\nnil|[1, \"s\"]|true\n");
+}
+
+#[test]
+fn fmt_needs_one_argument_per_placeholder() {
+    let mut vm = TestVm::new();
+
+    assert_eq!(vm.runtime_error(r#"fmt("{} {}", 1);"#), "fmt() has 2 placeholders but got 1 arguments.");
+    assert_eq!(vm.runtime_error(r#"fmt("{}", 1, 2);"#), "fmt() has 1 placeholders but got 2 arguments.");
+    assert_eq!(vm.runtime_error(r#"fmt("}");"#), "fmt() found an unmatched '}' in the format string.");
+    assert_eq!(vm.runtime_error("fmt(1);"), "fmt() expects a format string, got number.");
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use clox_rs::lox::compile_only;
+use common::run;
+
+/// The compile errors for `source`, formatted as they're reported.
+fn compile_errors(source: &str) -> Vec<String> {
+    match compile_only(source) {
+        Ok(_) => panic!("Expected compile errors"),
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+#[test]
+fn chained_comparisons_are_rejected_at_the_second_operator() {
+    assert_eq!(
+        compile_errors("print 1 < 2 < 3;"),
+        ["[line 1] Error at '<': Chained comparison is not allowed; use 'and'."]
+    );
+    assert_eq!(
+        compile_errors("var a = 1;\nprint a <= 2 > 0;"),
+        ["[line 2] Error at '>': Chained comparison is not allowed; use 'and'."]
+    );
+    assert_eq!(run("print 1 < 2 and 2 < 3;"), "true\n");
+}
+
+#[test]
+fn and_or_short_circuit_to_an_operand() {
+    let output = run(r#"
+        fun boom() { throw "evaluated"; }
+        print 1 < 2 and 3 < 2;
+        print false and boom();
+        print nil and boom();
+        print 1 or boom();
+        print nil or "fallback";
+        print false or nil;
+        print 1 and "last";
+    "#);
+
+    assert_eq!(output, "false\nfalse\nnil\n1\nfallback\nnil\nlast\n");
+}
+
+#[test]
+fn comma_evaluates_every_operand_and_keeps_the_last() {
+    let output = run(r#"
+        var a = 0;
+        var b = 0;
+        print (1, 2, 3);
+        var x = (a = 5, b = a + 1, a + b);
+        print x;
+        print a;
+        print b;
+    "#);
+
+    assert_eq!(output, "3\n11\n5\n6\n");
+}
+
+#[test]
+fn comma_binds_looser_than_assignment() {
+    let output = run(r#"
+        var a = 0;
+        var b = 0;
+        a = 1, b = 2;
+        print a;
+        print b;
+        fun second(p, q) { return q; }
+        print second((a, b), 3);
+    "#);
+
+    assert_eq!(output, "1\n2\n3\n");
+}
+
+#[test]
+fn print_separates_its_values_rather_than_using_the_comma_operator() {
+    assert_eq!(run("var a = 1; var b = 2; print a, b; print (a, b);"), "1 2\n2\n");
+}
+
+#[test]
+fn typeof_names_the_type_of_its_operand() {
+    let output = run(r#"
+        print typeof 1, typeof "a", typeof nil, typeof true;
+        print typeof ("a" + "b"), typeof [1], typeof {};
+        fun f() {}
+        class C { m() {} }
+        print typeof f, typeof C, typeof C(), typeof C().m, typeof len;
+        print typeof typeof 1;
+        print typeof -1 == "number";
+    "#);
+
+    assert_eq!(output, "\
+number string nil bool
+string list map
+function class instance function function
+string
+true
+");
+}
+
+#[test]
+fn nil_coalescing_only_evaluates_its_right_side_for_nil() {
+    let output = run(r#"
+        var calls = 0;
+        fun side() { calls = calls + 1; return "side"; }
+        print nil ?? 5;
+        print 3 ?? side();
+        print false ?? side();
+        print calls;
+        print nil ?? nil ?? "c";
+        print nil ?? "b" ?? side();
+        print calls;
+        print nil ?? side();
+        print calls;
+    "#);
+
+    // Only nil falls through; false is kept.
+    assert_eq!(output, "5\n3\nfalse\n0\nc\nb\n0\nside\n1\n");
+}
+
+#[test]
+fn increments_update_variables_and_evaluate_to_the_old_value() {
+    let output = run(r#"
+        var i = 0;
+        i++;
+        print i;
+        print i++;
+        print i;
+        print i--;
+        print i;
+        fun locals() { var j = 5; j--; j--; return j; }
+        print locals();
+        fun upvalues() { var k = 0; var bump = fun () { k++; return k; }; bump(); return bump(); }
+        print upvalues();
+    "#);
+
+    assert_eq!(output, "1\n1\n2\n2\n1\n3\n2\n");
+}
+
+#[test]
+fn only_variables_can_be_incremented() {
+    assert_eq!(compile_errors("1++;"), ["[line 1] Error at '++': Invalid increment target."]);
+    assert_eq!(compile_errors("var i = 0; (i)++;"), ["[line 1] Error at '++': Invalid increment target."]);
+    assert_eq!(compile_errors("var a = [1];\na[0]--;"), ["[line 2] Error at '--': Invalid increment target."]);
+}
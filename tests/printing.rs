@@ -0,0 +1,81 @@
+mod common;
+
+use common::{run, TestVm};
+
+#[test]
+fn numbers_print_naturally_unless_a_precision_is_set() {
+    let source = "print 1.0 / 3.0; print 2.0 / 3.0; print 12345; print 0.1 + 0.2; print 7;";
+    let mut vm = TestVm::new();
+    assert_eq!(vm.run(source), "0.3333333333333333\n0.6666666666666666\n12345\n0.30000000000000004\n7\n");
+
+    vm.vm.number_precision = Some(3);
+    assert_eq!(vm.run(source), "0.333\n0.667\n12300\n0.3\n7\n");
+
+    vm.vm.number_precision = Some(1);
+    assert_eq!(vm.run("print 1.0 / 3.0; print -2.7;"), "0.3\n-3\n");
+}
+
+#[test]
+fn print_writes_a_list_of_values_on_one_line() {
+    let output = run(r#"
+        print 1, 2, 3;
+        print "a", nil, true, 1 + 2;
+        print "alone";
+        for (i in [0, 1]) print i, i * 10;
+    "#);
+
+    assert_eq!(output, "1 2 3\na nil true 3\nalone\n0 0\n1 10\n");
+}
+
+#[test]
+fn print_json_writes_each_kind_of_value_as_json() {
+    let mut vm = TestVm::new();
+    vm.vm.print_json = true;
+    let output = vm.run("
+        print nil;
+        print true;
+        print 1.5;
+        print 0 / 0;
+        print \"\"\"say \"hi\"\n\ttab\\\"\"\";
+        print [1, \"two\", nil, [false]];
+        print {\"b\": 2, \"a\": \"x\", 3: nil};
+        fun f() {}
+        print f;
+        var list = [1];
+        list[0] = list;
+        print list;
+        print 1, \"a\";
+    ");
+
+    assert_eq!(output, concat!(
+        "null\n",
+        "true\n",
+        "1.5\n",
+        "null\n",
+        "\"say \\\"hi\\\"\\n\\ttab\\\\\"\n",
+        "[1,\"two\",null,[false]]\n",
+        "{\"3\":null,\"a\":\"x\",\"b\":2}\n",
+        "\"<fn f>\"\n",
+        "[null]\n",
+        "1 \"a\"\n",
+    ));
+}
+
+#[test]
+fn eprint_writes_to_the_error_sink() {
+    let mut vm = TestVm::new();
+    let output = vm.run(r#"
+        print "out";
+        eprint "diagnostic";
+        eprint 1 + 2;
+        print "more out";
+    "#);
+
+    assert_eq!(output, "out\nmore out\n");
+    assert_eq!(vm.errors.contents(), "diagnostic\n3\n");
+
+    vm.errors.clear();
+    vm.vm.print_json = true;
+    assert_eq!(vm.run(r#"eprint "json";"#), "");
+    assert_eq!(vm.errors.contents(), "\"json\"\n");
+}
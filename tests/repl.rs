@@ -0,0 +1,34 @@
+mod common;
+
+use clox_rs::lox::repl;
+use common::TestVm;
+
+#[test]
+fn repl_runs_meta_commands() {
+    let mut vm = TestVm::new();
+    let input = "var a = 1;\nprint a;\n:dump\n:help\n:reset\nprint a;\n:quit\nprint 2;\n";
+    let mut output = Vec::new();
+
+    repl(&mut vm.vm, input.as_bytes(), &mut output);
+    let output = String::from_utf8(output).unwrap();
+
+    // Lines are run as Lox until `:quit`, so the last one never runs.
+    assert_eq!(vm.output.contents(), "1\n");
+    assert!(output.starts_with("> > > == repl ==\n"));
+    assert!(output.contains("OP_DEFINE_GLOBAL"));
+    assert!(output.contains("> Unknown command ':help'. Available commands: :quit, :reset, :dump\n"));
+    assert!(output.ends_with("> > > "));
+    // `:reset` dropped the global.
+    assert!(vm.errors.contents().contains("Undefined variable 'a'."));
+}
+
+#[test]
+fn repl_stops_at_end_of_input() {
+    let mut vm = TestVm::new();
+    let mut output = Vec::new();
+
+    repl(&mut vm.vm, "print 1;\n".as_bytes(), &mut output);
+
+    assert_eq!(vm.output.contents(), "1\n");
+    assert_eq!(String::from_utf8(output).unwrap(), "> > \n");
+}
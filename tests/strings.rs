@@ -0,0 +1,59 @@
+mod common;
+
+use clox_rs::lox::compile_only;
+use common::{run, TestVm};
+
+/// The compile errors for `source`, formatted as they're reported.
+fn compile_errors(source: &str) -> Vec<String> {
+    match compile_only(source) {
+        Ok(_) => panic!("Expected compile errors"),
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+#[test]
+fn literal_and_built_strings_compare_by_content() {
+    let chunk = compile_only(r#"print "abc" == "abc";"#).ok().unwrap();
+    // Both operands are the same deduplicated literal, so equality only compares ids.
+    let disassembly = chunk.disassemble_to_string("equality");
+    assert!(disassembly.contains("0000    1 OP_STRING_LITERAL    0 'abc'\n0002    | OP_STRING_LITERAL    0 'abc'\n"), "{}", disassembly);
+
+    let output = run(r#"
+        print "abc" == "abc";
+        print "abc" == ("ab" + "c");
+        print ("a" + "b") == ("a" + "b");
+        print "abc" == "abd";
+        print ("ab" + "c") == "abd";
+    "#);
+    assert_eq!(output, "true\ntrue\ntrue\nfalse\nfalse\n");
+}
+
+#[test]
+fn triple_quoted_strings_keep_newlines_and_quotes() {
+    let mut vm = TestVm::new();
+    let output = vm.run(r#"var text = """line one
+it's "quoted"
+line three""";
+print text;
+print """""";"#);
+    assert_eq!(output, "line one\nit's \"quoted\"\nline three\n\n");
+
+    // Lines inside the literal still count towards the lines reported after it.
+    assert_eq!(vm.runtime_error("print \"\"\"a\nb\nc\"\"\";\nprint missing;"), "Undefined variable 'missing'.");
+    assert!(vm.errors.contents().starts_with("[line 4] Runtime Error"), "{}", vm.errors.contents());
+
+    assert_eq!(
+        compile_errors("print 1;\nvar s = \"\"\"never\nclosed;\n"),
+        ["[line 4] Error: Unterminated triple-quoted string."]
+    );
+}
+
+#[test]
+fn an_unterminated_string_only_swallows_its_own_line() {
+    assert_eq!(compile_errors("print 1;\nprint \"open;\nprint 2;\n"), ["[line 2] Error: Unterminated string."]);
+    // The statement after the bad string compiles, so the later mistake is the next error.
+    assert_eq!(
+        compile_errors("print \"open;\nprint 2;\nprint 3 +;\n"),
+        ["[line 1] Error: Unterminated string.", "[line 3] Error: Expect expression."]
+    );
+}